@@ -1,37 +1,159 @@
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 
-use crate::core::definition::Transformation;
+use crate::core::definition::{Instruction, Transformation};
 use crate::core::parser::parse;
-use arrow::array::RecordBatch;
+use arrow::array::{Int64Array, RecordBatch, StringArray};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
 use datafusion::{datasource::MemTable, error::DataFusionError, prelude::SessionContext};
 
 pub async fn execute(
     batches: Vec<RecordBatch>,
     transformations: &Transformation,
 ) -> Result<Vec<RecordBatch>, DataFusionError> {
-    let dataset_schema = batches.first().unwrap().schema();
-    let table = MemTable::try_new(dataset_schema, vec![batches])?;
+    execute_with_tables(batches, &HashMap::new(), transformations).await
+}
+
+/// Executes the transformation over `batches`, registered as `obs_table`, with every extra
+/// input of `tables` registered under its name so instructions can read it.
+pub async fn execute_with_tables(
+    batches: Vec<RecordBatch>,
+    tables: &HashMap<String, Vec<RecordBatch>>,
+    transformations: &Transformation,
+) -> Result<Vec<RecordBatch>, DataFusionError> {
+    let (_, results) = execute_with_schema(batches, tables, transformations).await?;
+    Ok(results)
+}
+
+/// Executes the transformation like `execute_with_tables`, also returning the schema of the
+/// results, taken from the plan when no record batches are produced.
+pub async fn execute_with_schema(
+    batches: Vec<RecordBatch>,
+    tables: &HashMap<String, Vec<RecordBatch>>,
+    transformations: &Transformation,
+) -> Result<(SchemaRef, Vec<RecordBatch>), DataFusionError> {
+    let batches = match transformations.instructions.first() {
+        Some(Instruction::SchemaDrift(expected)) => vec![schema_changes(&batches, expected)?],
+        _ => batches,
+    };
+    let table = MemTable::try_new(table_schema("obs_table", &batches)?, vec![batches])?;
     let ctx = SessionContext::new();
     ctx.register_table("obs_table", Arc::new(table))?;
+    let mut inputs = HashMap::new();
+    for (name, batches) in tables {
+        let table = MemTable::try_new(table_schema(name, batches)?, vec![batches.clone()])?;
+        ctx.register_table(name.as_str(), Arc::new(table))?;
+        inputs.insert(name.clone(), ctx.table(name.as_str()).await?);
+    }
     let table = ctx.table("obs_table").await?;
-    let logical_plan = parse(&transformations.instructions, table).await?;
-    logical_plan.clone().show().await?;
-    logical_plan.collect().await
+    let logical_plan = parse(&transformations.instructions, table, &inputs).await?;
+    let plan_schema = logical_plan.schema().inner().clone();
+    let results = logical_plan.collect().await?;
+    // the nullability of the plan may differ from the one of the executed batches
+    let schema = results.first().map_or(plan_schema, |batch| batch.schema());
+    Ok((schema, results))
+}
+
+/// Returns the schema of the table `name` from its first record batch.
+fn table_schema(name: &str, batches: &[RecordBatch]) -> Result<SchemaRef, DataFusionError> {
+    batches
+        .first()
+        .map(|batch| batch.schema())
+        .ok_or_else(|| DataFusionError::Plan(format!("Table {} has no record batches", name)))
+}
+
+/// Compares the schema of every batch with `expected`, each change is counted once.
+fn schema_changes(
+    batches: &[RecordBatch],
+    expected: &Schema,
+) -> Result<RecordBatch, DataFusionError> {
+    let nullability = |nullable: bool| if nullable { "nullable" } else { "not_null" };
+    // data types are rendered with commas, which separate the tags
+    let data_type = |data_type: &DataType| data_type.to_string().replace(", ", " ");
+    let mut changes: [(&str, BTreeSet<String>); 4] = [
+        ("added_columns", BTreeSet::new()),
+        ("removed_columns", BTreeSet::new()),
+        ("type_changes", BTreeSet::new()),
+        ("nullability_changes", BTreeSet::new()),
+    ];
+    for batch in batches {
+        let schema = batch.schema();
+        for field in schema.fields() {
+            match expected.field_with_name(field.name()) {
+                Ok(expected) => {
+                    if expected.data_type() != field.data_type() {
+                        changes[2].1.insert(format!(
+                            "{}:{}->{}",
+                            field.name(),
+                            data_type(expected.data_type()),
+                            data_type(field.data_type())
+                        ));
+                    }
+                    if expected.is_nullable() != field.is_nullable() {
+                        changes[3].1.insert(format!(
+                            "{}:{}->{}",
+                            field.name(),
+                            nullability(expected.is_nullable()),
+                            nullability(field.is_nullable())
+                        ));
+                    }
+                }
+                Err(_) => {
+                    changes[0].1.insert(field.name().to_string());
+                }
+            }
+        }
+        for field in expected.fields() {
+            if schema.field_with_name(field.name()).is_err() {
+                changes[1].1.insert(field.name().to_string());
+            }
+        }
+    }
+
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("change", DataType::Utf8, false),
+        Field::new("count", DataType::Int64, false),
+        Field::new("columns", DataType::Utf8, false),
+    ]));
+    Ok(RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(StringArray::from_iter_values(
+                changes.iter().map(|(change, _)| *change),
+            )),
+            Arc::new(Int64Array::from_iter_values(
+                changes.iter().map(|(_, columns)| columns.len() as i64),
+            )),
+            Arc::new(StringArray::from_iter_values(changes.iter().map(
+                |(_, columns)| columns.iter().cloned().collect::<Vec<_>>().join("|"),
+            ))),
+        ],
+    )?)
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashMap;
+    use std::ops::Bound;
     use std::sync::Arc;
 
-    use crate::core::definition::{AggregateType, TransformationBuilder};
-    use crate::test::{assert_record_batches_equal, generate_dataset};
-    use arrow::array::Int64Array;
+    use crate::core::definition::{
+        AggregateType, Buckets, BuiltInMetricsBuilder, PairAggregateType, QuantileMode,
+        SequenceStep, TransformationBuilder,
+    };
+    use crate::test::{
+        assert_record_batches_equal, column_as_f64, column_as_string, generate_dataset,
+        generate_events_dataset, generate_nested_dataset, generate_reference_dataset,
+        generate_text_dataset, metrics_by_name,
+    };
+    use arrow::array::{Int32Array, Int64Array, TimestampNanosecondArray};
     use arrow::{
         array::{RecordBatch, StringArray},
-        datatypes::{DataType, Field, Schema},
+        datatypes::{DataType, Field, Schema, TimeUnit},
     };
+    use std::time::Duration;
 
-    use super::execute;
+    use super::{execute, execute_with_tables};
 
     #[tokio::test]
     async fn test_execute_dataset() {
@@ -93,4 +215,1077 @@ mod test {
 
         assert_record_batches_equal(result, expected_result);
     }
+
+    #[tokio::test]
+    async fn test_execute_without_record_batches() {
+        let transform = BuiltInMetricsBuilder::new().count_total(None, None);
+
+        let error = execute(Vec::new(), &transform).await.unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Table obs_table has no record batches"));
+
+        let tables = HashMap::from([("parent".to_string(), Vec::new())]);
+        let error = execute_with_tables(vec![generate_dataset().unwrap()], &tables, &transform)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Table parent has no record batches"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_null_count_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        let null_count = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_null("value", None),
+        )
+        .await
+        .unwrap();
+        let non_null_count = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_non_null("value", None),
+        )
+        .await
+        .unwrap();
+        let completeness = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().completeness("value", Some(vec!["env=test"])),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(column_as_f64(&null_count, "value"), vec![Some(1.0)]);
+        assert_eq!(column_as_f64(&non_null_count, "value"), vec![Some(4.0)]);
+        assert_eq!(column_as_f64(&completeness, "value"), vec![Some(0.8)]);
+        assert_eq!(
+            completeness[0]
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| f.name().as_str())
+                .collect::<Vec<_>>(),
+            vec!["value", "metric_name", "tags", "system_ts", "event_ts"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_count_total_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        let total = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_total(None, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(column_as_f64(&total, "value"), vec![Some(5.0)]);
+
+        let per_category = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().count_total(Some(vec!["category"]), None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            per_category[0].schema().fields().len(),
+            total[0].schema().fields().len()
+        );
+        let mut counts: Vec<(String, f64)> = column_as_string(&per_category, "tags")
+            .into_iter()
+            .zip(column_as_f64(&per_category, "value"))
+            .map(|(category, value)| (category.unwrap(), value.unwrap()))
+            .collect();
+        counts.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            counts,
+            vec![
+                ("category=a".to_string(), 2.0),
+                ("category=b".to_string(), 2.0),
+                ("category=c".to_string(), 1.0)
+            ]
+        );
+
+        // dimension values are percent-encoded as the values of top_k
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "category",
+            DataType::Utf8,
+            true,
+        )]));
+        let record_batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(StringArray::from(vec![Some("x,y"), None]))],
+        )
+        .unwrap();
+        let per_category = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().count_total(Some(vec!["category"]), None),
+        )
+        .await
+        .unwrap();
+        let mut tags = column_as_string(&per_category, "tags");
+        tags.sort();
+        assert_eq!(
+            tags,
+            vec![
+                Some("category=null".to_string()),
+                Some("category=x%2Cy".to_string())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_count_distinct_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        let single = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_distinct(vec!["category"], None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(column_as_f64(&single, "value"), vec![Some(3.0)]);
+        assert_eq!(
+            column_as_string(&single, "metric_name"),
+            vec![Some("category_count_distinct".to_string())]
+        );
+
+        let tuples = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_distinct(vec!["category", "value"], None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(column_as_f64(&tuples, "value"), vec![Some(5.0)]);
+
+        // the null of a single column is skipped, unlike the tuple (a, null) above
+        let nullable = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().count_distinct(vec!["value"], None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(column_as_f64(&nullable, "value"), vec![Some(4.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_count_duplicate_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_duplicate(vec!["category"], None, None),
+        )
+        .await
+        .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["category_duplicate_count"], 4.0);
+        assert_eq!(metrics["category_duplicate_keys"], 2.0);
+
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().count_duplicate(
+                vec!["category"],
+                Some(1),
+                Some(vec!["env=test"]),
+            ),
+        )
+        .await
+        .unwrap();
+        let samples: Vec<(String, f64)> = column_as_string(&result, "metric_name")
+            .into_iter()
+            .zip(column_as_string(&result, "tags"))
+            .zip(column_as_f64(&result, "value"))
+            .filter(|((name, _), _)| name.as_deref() == Some("category_duplicate_sample"))
+            .map(|((_, tags), value)| (tags.unwrap(), value.unwrap()))
+            .collect();
+        assert_eq!(samples, vec![("env=test,category=a".to_string(), 2.0)]);
+
+        // the sample is bounded per window, each of them holding duplicates of its own
+        let minute = 60_000_000_000;
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(
+                "event_time",
+                DataType::Timestamp(TimeUnit::Nanosecond, None),
+                false,
+            ),
+            Field::new("id", DataType::Int32, false),
+        ]));
+        let record_batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(TimestampNanosecondArray::from(vec![
+                    10 * minute,
+                    20 * minute,
+                    30 * minute,
+                    40 * minute,
+                    70 * minute,
+                    80 * minute,
+                ])),
+                Arc::new(Int32Array::from(vec![1, 1, 2, 2, 3, 3])),
+            ],
+        )
+        .unwrap();
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new()
+                .window("event_time", Duration::from_secs(3600))
+                .count_duplicate(vec!["id"], Some(1), None),
+        )
+        .await
+        .unwrap();
+        let mut samples: Vec<String> = column_as_string(&result, "metric_name")
+            .into_iter()
+            .zip(column_as_string(&result, "tags"))
+            .filter(|(name, _)| name.as_deref() == Some("id_duplicate_sample"))
+            .map(|(_, tags)| tags.unwrap())
+            .collect();
+        samples.sort();
+        assert_eq!(samples, vec!["id=1", "id=3"]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_zscore_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().zscore("value", 1.0, None),
+        )
+        .await
+        .unwrap();
+        let metrics = metrics_by_name(&result);
+        // mean 7.2 and sample stddev ~4.59, only 2.0 and 12.3 are further than one stddev
+        assert_eq!(metrics["value_zscore_outliers"], 2.0);
+        assert!((metrics["value_zscore_max"] - 1.133).abs() < 1e-3);
+    }
+
+    #[tokio::test]
+    async fn test_execute_join_aggregate() {
+        let record_batch = generate_dataset().unwrap();
+        let transform = TransformationBuilder::new()
+            .join_aggregate(AggregateType::Max, vec!["id"], vec!["category"])
+            .filter("id = id_max")
+            .select(vec!["category", "id"])
+            .build();
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let mut ids = column_as_f64(&result, "id");
+        ids.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(ids, vec![Some(2.0), Some(4.0), Some(5.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_join_aggregate_with_null_keys() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int32, false),
+            Field::new("category", DataType::Utf8, true),
+        ]));
+        let record_batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3, 4])),
+                Arc::new(StringArray::from(vec![Some("a"), None, Some("a"), None])),
+            ],
+        )
+        .unwrap();
+        let transform = TransformationBuilder::new()
+            .join_aggregate(AggregateType::Max, vec!["id"], vec!["category"])
+            .select(vec!["id", "id_max"])
+            .build();
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let mut rows: Vec<(Option<f64>, Option<f64>)> = column_as_f64(&result, "id")
+            .into_iter()
+            .zip(column_as_f64(&result, "id_max"))
+            .collect();
+        rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            rows,
+            vec![
+                (Some(1.0), Some(3.0)),
+                (Some(2.0), Some(4.0)),
+                (Some(3.0), Some(3.0)),
+                (Some(4.0), Some(4.0))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_windowed_metrics() {
+        let record_batch = generate_events_dataset().unwrap();
+        let hour = Duration::from_secs(3600);
+
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new()
+                .window("event_time", hour)
+                .count_null("value", None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result[0]
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| f.name().as_str())
+                .collect::<Vec<_>>(),
+            vec!["value", "metric_name", "tags", "system_ts", "event_ts"]
+        );
+        // windows of a naive event time are in UTC, as the `now()` of non-windowed metrics
+        assert_eq!(
+            result[0]
+                .schema()
+                .field_with_name("event_ts")
+                .unwrap()
+                .data_type(),
+            &DataType::Timestamp(TimeUnit::Nanosecond, Some("+00:00".into()))
+        );
+        let result_unwindowed = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_null("value", None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result[0]
+                .schema()
+                .field_with_name("event_ts")
+                .unwrap()
+                .data_type(),
+            result_unwindowed[0]
+                .schema()
+                .field_with_name("event_ts")
+                .unwrap()
+                .data_type()
+        );
+        let mut windows: Vec<(String, f64)> = column_as_string(&result, "event_ts")
+            .into_iter()
+            .zip(column_as_f64(&result, "value"))
+            .map(|(ts, value)| (ts.unwrap(), value.unwrap()))
+            .collect();
+        windows.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            windows,
+            vec![
+                ("1970-01-01T00:00:00Z".to_string(), 1.0),
+                ("1970-01-01T01:00:00Z".to_string(), 1.0),
+                ("1970-01-01T02:00:00Z".to_string(), 0.0),
+            ]
+        );
+
+        // no window holds duplicates, each of them still gets its rows
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new()
+                .window("event_time", hour)
+                .count_duplicate(vec!["value"], None, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(column_as_f64(&result, "value"), vec![Some(0.0); 6]);
+
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new()
+                .window("event_time", hour)
+                .count_total(None, None),
+        )
+        .await
+        .unwrap();
+        let mut counts = column_as_f64(&result, "value");
+        counts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(counts, vec![Some(1.0), Some(2.0), Some(2.0)]);
+
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new()
+                .window("event_time", hour)
+                .zscore("value", 3.0, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.iter().map(|b| b.num_rows()).sum::<usize>(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_execute_quantile_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        for mode in [QuantileMode::Exact, QuantileMode::Approximate] {
+            let result = execute(
+                vec![record_batch.clone()],
+                &BuiltInMetricsBuilder::new().quantiles(
+                    "value",
+                    vec![0.0, 0.5, 1.0],
+                    mode.clone(),
+                    None,
+                ),
+            )
+            .await
+            .unwrap();
+            let metrics = metrics_by_name(&result);
+            assert_eq!(metrics["value_p0"], 2.0);
+            assert!((metrics["value_p100"] - 12.3).abs() < 1e-5);
+            match mode {
+                QuantileMode::Exact => assert_eq!(metrics["value_p50"], 7.25),
+                // the t-digest interpolates between centroids instead of ranks
+                QuantileMode::Approximate => {
+                    assert!(metrics["value_p50"] > 5.0 && metrics["value_p50"] < 9.5)
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_quantile_aggregate() {
+        let record_batch = generate_dataset().unwrap();
+        let transform = TransformationBuilder::new()
+            .aggregate(AggregateType::Quantile(0.5), vec!["value"])
+            .group_by(vec!["category"])
+            .build();
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let mut medians: Vec<f64> = column_as_f64(&result, "value")
+            .into_iter()
+            .map(|v| (v.unwrap() * 100.0).round() / 100.0)
+            .collect();
+        medians.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(medians, vec![2.0, 8.65, 9.5]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_histogram_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().histogram(
+                "value",
+                Buckets::Explicit(vec![10.0, 5.0]),
+                None,
+            ),
+        )
+        .await
+        .unwrap();
+        let metrics: HashMap<(String, String), f64> = column_as_string(&result, "metric_name")
+            .into_iter()
+            .zip(column_as_string(&result, "tags"))
+            .zip(column_as_f64(&result, "value"))
+            .map(|((name, tags), value)| ((name.unwrap(), tags.unwrap()), value.unwrap()))
+            .collect();
+        let metric = |name: &str, tags: &str| metrics[&(name.to_string(), tags.to_string())];
+        assert_eq!(metric("value_histogram_bucket", "le=5"), 2.0);
+        assert_eq!(metric("value_histogram_bucket", "le=10"), 3.0);
+        assert_eq!(metric("value_histogram_bucket", "le=+Inf"), 4.0);
+        assert_eq!(metric("value_histogram_bucket_count", "le=10"), 1.0);
+        assert_eq!(metric("value_histogram_bucket_count", "le=+Inf"), 1.0);
+        assert_eq!(metric("value_histogram_count", ""), 4.0);
+        assert!((metric("value_histogram_sum", "") - 28.8).abs() < 1e-5);
+
+        let transform = TransformationBuilder::new()
+            .histogram(
+                vec!["value"],
+                Buckets::Linear {
+                    start: 5.0,
+                    width: 5.0,
+                    count: 2,
+                },
+            )
+            .group_by(vec!["category"])
+            .build();
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        assert_eq!(result[0].num_columns(), 9);
+        let mut counts = column_as_f64(&result, "value_count");
+        counts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(counts, vec![Some(1.0), Some(1.0), Some(2.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_string_metrics() {
+        let record_batch = generate_text_dataset().unwrap();
+
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().string_lengths("email", None),
+        )
+        .await
+        .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["email_length_min"], 2.0);
+        assert_eq!(metrics["email_length_max"], 16.0);
+        assert_eq!(metrics["email_length_avg"], 11.5);
+
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_blank("email", None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(column_as_f64(&result, "value"), vec![Some(1.0)]);
+
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().pattern_match_ratio(
+                "email",
+                r"^[^@\s]+@[^@\s]+\.[a-z]+$",
+                None,
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(column_as_f64(&result, "value"), vec![Some(0.5)]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_freshness_metrics() {
+        let record_batch = generate_events_dataset().unwrap();
+
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().freshness("event_time", None, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            result[0]
+                .schema()
+                .fields()
+                .iter()
+                .map(|f| f.name().as_str())
+                .collect::<Vec<_>>(),
+            vec!["value", "metric_name", "tags", "system_ts", "event_ts"]
+        );
+        let metrics = metrics_by_name(&result);
+        let newest = 125.0 * 60.0;
+        assert_eq!(metrics["event_time_newest"], newest);
+        // without a processing time every record is compared with the execution time
+        assert_eq!(
+            metrics["event_time_lag_seconds_max"] - metrics["event_time_lag_seconds_min"],
+            newest - 10.0 * 60.0
+        );
+        assert!(column_as_string(&result, "event_ts")
+            .iter()
+            .all(|ts| ts.as_deref() == Some("1970-01-01T02:05:00Z")));
+
+        // events at minutes 0, 10 and 20 processed 1, 5 and 3 minutes later, the last one twice
+        let minute = 60_000_000_000;
+        let timestamp = || DataType::Timestamp(TimeUnit::Nanosecond, None);
+        let record_batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("event_time", timestamp(), false),
+                Field::new("ingested_at", timestamp(), true),
+            ])),
+            vec![
+                Arc::new(TimestampNanosecondArray::from(vec![
+                    0,
+                    10 * minute,
+                    20 * minute,
+                    20 * minute,
+                ])),
+                Arc::new(TimestampNanosecondArray::from(vec![
+                    Some(minute),
+                    Some(15 * minute),
+                    Some(23 * minute),
+                    None,
+                ])),
+            ],
+        )
+        .unwrap();
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().freshness("event_time", Some("ingested_at"), None),
+        )
+        .await
+        .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["event_time_lag_seconds_min"], 60.0);
+        assert_eq!(metrics["event_time_lag_seconds_avg"], 180.0);
+        assert_eq!(metrics["event_time_lag_seconds_max"], 300.0);
+        assert!(metrics["event_time_age_seconds"] > metrics["event_time_lag_seconds_max"]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_conformance_metrics() {
+        let record_batch = generate_dataset().unwrap();
+
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_not_allowed("category", vec!["a", "b"], None),
+        )
+        .await
+        .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["category_not_allowed_count"], 1.0);
+        assert_eq!(metrics["category_not_allowed_ratio"], 0.2);
+
+        // with nothing allowed every non-null value is counted, the null one is not
+        let result = execute(
+            vec![record_batch.clone()],
+            &BuiltInMetricsBuilder::new().count_not_allowed("value", Vec::<f32>::new(), None),
+        )
+        .await
+        .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["value_not_allowed_count"], 4.0);
+        assert_eq!(metrics["value_not_allowed_ratio"], 1.0);
+
+        let result = execute(
+            vec![record_batch],
+            &BuiltInMetricsBuilder::new().count_out_of_range(
+                "value",
+                Bound::Excluded(2.0_f32),
+                Bound::Included(9.5_f32),
+                None,
+            ),
+        )
+        .await
+        .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["value_out_of_range_count"], 2.0);
+        assert_eq!(metrics["value_out_of_range_ratio"], 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_execute_merge_sketches() {
+        let record_batch = generate_dataset().unwrap();
+
+        let mut hourly = Vec::new();
+        for columns in [vec!["category"], vec!["id"], vec!["id"]] {
+            let result = execute(
+                vec![record_batch.clone()],
+                &BuiltInMetricsBuilder::new().approx_count_distinct(columns, None),
+            )
+            .await
+            .unwrap();
+            assert_eq!(result[0].schema().field(5).name(), "sketch");
+            hourly.extend(result);
+        }
+        let estimates = column_as_f64(&hourly, "value");
+        assert_eq!(estimates[0].unwrap().round(), 3.0);
+        assert_eq!(estimates[1].unwrap().round(), 5.0);
+
+        // the same ids seen in two executions are only counted once
+        let result = execute(hourly, &BuiltInMetricsBuilder::new().merge_sketches())
+            .await
+            .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics["category_approx_count_distinct"].round(), 3.0);
+        assert_eq!(metrics["id_approx_count_distinct"].round(), 5.0);
+    }
+
+    #[tokio::test]
+    async fn test_execute_top_k_metrics() {
+        let record_batch = generate_dataset().unwrap();
+        let transform = BuiltInMetricsBuilder::new().top_k("category", 2, Some(vec!["env=test"]));
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let metrics: HashMap<(String, String), f64> = column_as_string(&result, "metric_name")
+            .into_iter()
+            .zip(column_as_string(&result, "tags"))
+            .zip(column_as_f64(&result, "value"))
+            .map(|((name, tags), value)| ((name.unwrap(), tags.unwrap()), value.unwrap()))
+            .collect();
+        let metric = |name: &str, tags: &str| metrics[&(name.to_string(), tags.to_string())];
+
+        assert_eq!(metrics.len(), 5);
+        assert_eq!(metric("category_top_count", "env=test,value=a,rank=1"), 2.0);
+        assert_eq!(metric("category_top_count", "env=test,value=b,rank=2"), 2.0);
+        assert_eq!(metric("category_top_share", "env=test,value=a,rank=1"), 0.4);
+        assert_eq!(metric("category_top_share", "env=test,value=b,rank=2"), 0.4);
+        let entropy = -(2.0 * 0.4 * 0.4_f64.ln() + 0.2 * 0.2_f64.ln());
+        assert!((metric("category_entropy", "env=test") - entropy).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_execute_top_k_escapes_tags() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "category",
+            DataType::Utf8,
+            true,
+        )]));
+        let record_batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(StringArray::from(vec!["x,y", "x,y", "a=b%"]))],
+        )
+        .unwrap();
+        let transform = BuiltInMetricsBuilder::new().top_k("category", 2, None);
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let mut tags: Vec<String> = column_as_string(&result, "tags")
+            .into_iter()
+            .flatten()
+            .filter(|t| !t.is_empty())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        assert_eq!(tags, vec!["value=a%3Db%25,rank=2", "value=x%2Cy,rank=1"]);
+    }
+
+    #[tokio::test]
+    async fn test_execute_correlation_metrics() {
+        let record_batch = generate_dataset().unwrap();
+        let transform = BuiltInMetricsBuilder::new().correlation_all(
+            record_batch.schema().as_ref(),
+            true,
+            None,
+        );
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let metrics = metrics_by_name(&result);
+        // the record with a null value is skipped, ids 1, 3, 4 and 5 remain
+        let (x, y) = (
+            [1.0, 3.0, 4.0, 5.0],
+            [2.0_f32, 5.0, 12.3, 9.5].map(f64::from),
+        );
+        let (mx, my) = (x.iter().sum::<f64>() / 4.0, y.iter().sum::<f64>() / 4.0);
+        let sxy: f64 = x.iter().zip(&y).map(|(a, b)| (a - mx) * (b - my)).sum();
+        let sxx: f64 = x.iter().map(|a| (a - mx).powi(2)).sum();
+        let syy: f64 = y.iter().map(|b| (b - my).powi(2)).sum();
+        assert_eq!(metrics.len(), 2);
+        assert!((metrics["id_value_covariance"] - sxy / 3.0).abs() < 1e-9);
+        assert!((metrics["id_value_correlation"] - sxy / (sxx * syy).sqrt()).abs() < 1e-9);
+
+        let transform = TransformationBuilder::new()
+            .pair_aggregate(PairAggregateType::Correlation, vec![("id", "value")])
+            .group_by(Vec::new())
+            .build();
+        let result = execute(vec![generate_dataset().unwrap()], &transform)
+            .await
+            .unwrap();
+        assert_eq!(
+            column_as_f64(&result, "id_value_correlation"),
+            vec![Some(metrics["id_value_correlation"])]
+        );
+
+        // a single numeric column has nothing to be correlated with
+        let record_batch = generate_events_dataset().unwrap();
+        for mut builder in [
+            BuiltInMetricsBuilder::new(),
+            BuiltInMetricsBuilder::new().window("event_time", Duration::from_secs(3600)),
+        ] {
+            let transform = builder.correlation_all(record_batch.schema().as_ref(), true, None);
+            let result = execute(vec![record_batch.clone()], &transform)
+                .await
+                .unwrap();
+            assert_eq!(result.iter().map(|b| b.num_rows()).sum::<usize>(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_drift_metrics() {
+        let tables = HashMap::from([(
+            "reference".to_string(),
+            vec![generate_reference_dataset().unwrap()],
+        )]);
+        let mut metrics: HashMap<String, f64> = HashMap::new();
+        for (column, buckets) in [
+            ("category", None),
+            ("value", Some(Buckets::Explicit(vec![5.0, 10.0]))),
+        ] {
+            let result = execute_with_tables(
+                vec![generate_dataset().unwrap()],
+                &tables,
+                &BuiltInMetricsBuilder::new().drift(column, "reference", buckets, None),
+            )
+            .await
+            .unwrap();
+            metrics.extend(metrics_by_name(&result));
+        }
+        assert_eq!(metrics.len(), 5);
+
+        let psi = |p: &[f64], q: &[f64]| -> f64 {
+            p.iter().zip(q).map(|(p, q)| (p - q) * (p / q).ln()).sum()
+        };
+        let kl =
+            |p: &[f64], q: &[f64]| -> f64 { p.iter().zip(q).map(|(p, q)| p * (p / q).ln()).sum() };
+        // categories a, b, c and d
+        let (p, q) = ([0.4, 0.4, 0.2, 0.0001], [0.25, 0.5, 0.0001, 0.25]);
+        assert!((metrics["category_psi"] - psi(&p, &q)).abs() < 1e-9);
+        assert!((metrics["category_kl_divergence"] - kl(&p, &q)).abs() < 1e-9);
+        // buckets null, (-Inf, 5], (5, 10] and (10, +Inf)
+        let (p, q) = ([0.2, 0.4, 0.2, 0.2], [0.0001, 0.5, 0.25, 0.25]);
+        assert!((metrics["value_psi"] - psi(&p, &q)).abs() < 1e-9);
+        assert!((metrics["value_kl_divergence"] - kl(&p, &q)).abs() < 1e-9);
+        assert!((metrics["value_ks"] - 0.25).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_execute_schema_drift() {
+        let expected = Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("category", DataType::Utf8, true),
+            Field::new("value", DataType::Float32, true),
+            Field::new("created_at", DataType::Utf8, true),
+        ]);
+        let transform =
+            BuiltInMetricsBuilder::new().schema_drift(expected, Some(vec!["source=orders"]));
+
+        // batches with different schemas are compared one by one
+        let batches = vec![
+            generate_dataset().unwrap(),
+            generate_text_dataset().unwrap(),
+        ];
+        let result = execute(batches, &transform).await.unwrap();
+        let metrics: HashMap<String, (f64, String)> = column_as_string(&result, "metric_name")
+            .into_iter()
+            .zip(column_as_f64(&result, "value"))
+            .zip(column_as_string(&result, "tags"))
+            .map(|((name, value), tags)| (name.unwrap(), (value.unwrap(), tags.unwrap())))
+            .collect();
+        assert_eq!(
+            metrics["schema_added_columns"],
+            (1.0, "source=orders,columns=email".to_string())
+        );
+        assert_eq!(
+            metrics["schema_removed_columns"],
+            (
+                4.0,
+                "source=orders,columns=category|created_at|id|value".to_string()
+            )
+        );
+        assert_eq!(
+            metrics["schema_type_changes"],
+            (1.0, "source=orders,columns=id:Int64->Int32".to_string())
+        );
+        assert_eq!(
+            metrics["schema_nullability_changes"],
+            (
+                1.0,
+                "source=orders,columns=category:nullable->not_null".to_string()
+            )
+        );
+
+        // a reused builder pushes the schema drift after its previous instructions
+        let mut builder = BuiltInMetricsBuilder::new();
+        builder.count_total(None, None);
+        let transform = builder.schema_drift(Schema::empty(), None);
+        let error = execute(vec![generate_dataset().unwrap()], &transform)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("Schema drift must be the first instruction"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_referential_integrity() {
+        let parent = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("code", DataType::Utf8, false)])),
+            vec![Arc::new(StringArray::from(vec!["a", "b", "b", "c"]))],
+        )
+        .unwrap();
+        // d is referenced twice and e once, the null category is not checked
+        let child = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new(
+                "category",
+                DataType::Utf8,
+                true,
+            )])),
+            vec![Arc::new(StringArray::from(vec![
+                Some("a"),
+                Some("d"),
+                Some("b"),
+                Some("d"),
+                Some("e"),
+                None,
+            ]))],
+        )
+        .unwrap();
+        let tables = HashMap::from([("categories".to_string(), vec![parent])]);
+        let transform = BuiltInMetricsBuilder::new().referential_integrity(
+            "categories",
+            vec![("category", "code")],
+            Some(1),
+            None,
+        );
+
+        let result = execute_with_tables(vec![child], &tables, &transform)
+            .await
+            .unwrap();
+        let metrics: HashMap<String, (f64, String)> = column_as_string(&result, "metric_name")
+            .into_iter()
+            .zip(column_as_f64(&result, "value"))
+            .zip(column_as_string(&result, "tags"))
+            .map(|((name, value), tags)| (name.unwrap(), (value.unwrap(), tags.unwrap())))
+            .collect();
+        assert_eq!(metrics.len(), 3);
+        assert_eq!(metrics["category_orphan_count"].0, 3.0);
+        assert_eq!(metrics["category_orphan_ratio"].0, 0.6);
+        assert_eq!(
+            metrics["category_orphan_sample"],
+            (2.0, "category=d".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_execute_profile() {
+        let record_batch = generate_dataset().unwrap();
+        let transform = BuiltInMetricsBuilder::new().profile(record_batch.schema().as_ref(), None);
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        assert_eq!(result[0].schema().field(0).data_type(), &DataType::Float64);
+        let metrics = metrics_by_name(&result);
+        let mut names: Vec<&str> = metrics.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec![
+                "category_count_distinct",
+                "category_count_null",
+                "category_length_avg",
+                "category_length_max",
+                "category_length_min",
+                "id_count_distinct",
+                "id_count_null",
+                "id_max",
+                "id_mean",
+                "id_min",
+                "value_count_distinct",
+                "value_count_null",
+                "value_max",
+                "value_mean",
+                "value_min",
+            ]
+        );
+        assert_eq!(metrics["value_count_null"], 1.0);
+        assert_eq!(metrics["category_count_distinct"], 3.0);
+        assert_eq!(metrics["id_mean"], 3.0);
+        assert_eq!(metrics["category_length_max"], 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_execute_windowed_profile() {
+        let record_batch = generate_events_dataset().unwrap();
+        let transform = BuiltInMetricsBuilder::new()
+            .window("event_time", Duration::from_secs(3600))
+            .profile(record_batch.schema().as_ref(), None);
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        // the event time column is both profiled and windowed on
+        let values = |metric: &str| -> Vec<f64> {
+            column_as_string(&result, "metric_name")
+                .into_iter()
+                .zip(column_as_f64(&result, "value"))
+                .filter(|(name, _)| name.as_deref() == Some(metric))
+                .map(|(_, value)| value.unwrap())
+                .collect()
+        };
+        assert_eq!(values("event_time_count_null"), vec![0.0; 3]);
+        assert_eq!(values("value_count_null").iter().sum::<f64>(), 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_execute_sequence_gaps() {
+        // sequence numbers 3 and 4 arrived swapped, 3 never made it
+        let record_batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("offset", DataType::Int32, false),
+                Field::new("sequence", DataType::Int32, true),
+            ])),
+            vec![
+                Arc::new(Int32Array::from(vec![1, 2, 3, 4, 5, 6])),
+                Arc::new(Int32Array::from(vec![
+                    Some(1),
+                    Some(2),
+                    Some(5),
+                    Some(4),
+                    None,
+                    Some(7),
+                ])),
+            ],
+        )
+        .unwrap();
+        let transform = BuiltInMetricsBuilder::new().sequence_gaps(
+            "sequence",
+            SequenceStep::Integer(1),
+            Some("offset"),
+            None,
+        );
+
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["sequence_gap_count"], 2.0);
+        assert_eq!(metrics["sequence_gap_max"], 3.0);
+        assert_eq!(metrics["sequence_non_monotonic_count"], 1.0);
+
+        // offsets above 2^53 are not exact as Float64, 2^60 + 4 is missing
+        let base = 1_i64 << 60;
+        let record_batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("s", DataType::Int64, false)])),
+            vec![Arc::new(Int64Array::from(
+                [0, 1, 2, 3, 5].map(|i| base + i).to_vec(),
+            ))],
+        )
+        .unwrap();
+        let transform =
+            BuiltInMetricsBuilder::new().sequence_gaps("s", SequenceStep::Integer(1), None, None);
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["s_gap_count"], 1.0);
+        assert_eq!(metrics["s_gap_max"], 2.0);
+
+        // events arrive at minutes 10, 50, 80, 90 and 125
+        let transform = BuiltInMetricsBuilder::new().sequence_gaps(
+            "event_time",
+            SequenceStep::Duration(Duration::from_secs(30 * 60)),
+            None,
+            None,
+        );
+        let result = execute(vec![generate_events_dataset().unwrap()], &transform)
+            .await
+            .unwrap();
+        let metrics = metrics_by_name(&result);
+        assert_eq!(metrics["event_time_gap_count"], 2.0);
+        assert_eq!(metrics["event_time_gap_max"], 40.0 * 60.0);
+        // without an arrival order the values are sorted, they cannot go backwards
+        assert!(!metrics.contains_key("event_time_non_monotonic_count"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_nested_metrics() {
+        let record_batch = generate_nested_dataset().unwrap();
+
+        let mut metrics: HashMap<String, f64> = HashMap::new();
+        for transform in [
+            BuiltInMetricsBuilder::new().count_null("payload.user.id", None),
+            BuiltInMetricsBuilder::new().list_lengths("payload.items", None),
+            BuiltInMetricsBuilder::new().count_distinct_keys("attributes", None),
+        ] {
+            let result = execute(vec![record_batch.clone()], &transform)
+                .await
+                .unwrap();
+            metrics.extend(metrics_by_name(&result));
+        }
+        assert_eq!(metrics["payload.user.id_count_null"], 1.0);
+        assert_eq!(metrics["payload.items_length_min"], 0.0);
+        assert_eq!(metrics["payload.items_length_max"], 2.0);
+        assert_eq!(metrics["payload.items_length_avg"], 1.0);
+        assert_eq!(metrics["attributes_key_count_distinct"], 3.0);
+
+        let transform = TransformationBuilder::new()
+            .aggregate(AggregateType::Sum, vec!["payload.user.id"])
+            .group_by(vec![])
+            .build();
+        let result = execute(vec![record_batch.clone()], &transform)
+            .await
+            .unwrap();
+        assert_eq!(column_as_f64(&result, "payload.user.id"), vec![Some(4.0)]);
+
+        let transform = BuiltInMetricsBuilder::new().profile(record_batch.schema().as_ref(), None);
+        let result = execute(vec![record_batch], &transform).await.unwrap();
+        let names = column_as_string(&result, "metric_name");
+        for name in [
+            "payload_count_null",
+            "payload.user.id_mean",
+            "payload.items_length_max",
+            "attributes_key_count_distinct",
+        ] {
+            assert!(names.contains(&Some(name.to_string())), "missing {}", name);
+        }
+    }
 }
@@ -1,14 +1,49 @@
-use datafusion::logical_expr::Literal;
-use datafusion::prelude::{col, current_time, lit, Expr};
+use std::fmt::Display;
+use std::ops::Bound;
+use std::time::Duration;
+
+use arrow::datatypes::{DataType, Schema, SchemaRef, TimeUnit};
+use datafusion::common::ScalarValue;
+use datafusion::functions::expr_fn::{
+    abs, character_length, coalesce, concat, concat_ws, date_bin, date_part, ln, now, nullif,
+    r#struct, regexp_like, replace,
+};
+use datafusion::functions_aggregate::sum::sum_udaf;
+use datafusion::logical_expr::expr::WindowFunction;
+use datafusion::logical_expr::window_function::{lag, row_number};
+use datafusion::logical_expr::{cast, when, ExprFunctionExt, JoinType, Literal};
+use datafusion::prelude::{cardinality, col, get_field, ident, lit, Expr};
+
+use crate::core::functions::{hll_estimate, map_keys};
+use crate::core::sketch::DEFAULT_PRECISION;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Select(Vec<Expr>),
     GroupBy(Vec<Expr>),
     Aggregate(AggregateType, Vec<ExprValue>),
+    JoinAggregate(Vec<Expr>, Vec<(AggregateType, ExprValue)>),
+    Histogram(ExprValue, Vec<f64>),
+    /// Aggregates pairs of expressions, computed at the next group by like `Aggregate`.
+    PairAggregate(PairAggregateType, Vec<PairValue>),
     Filter(String),
     Literal(String, Expr),
     NewCol(String, Expr),
+    DropCol(String),
+    Unpivot(Vec<Expr>, Vec<MetricValue>),
+    Union(Vec<Vec<Instruction>>),
+    /// Replaces the dataset with the extra input registered under the given name.
+    Table(String),
+    /// Expands every element of the given list column into its own row.
+    Unnest(String),
+    /// Joins the dataset with the result of the instructions, which start from the dataset too,
+    /// on the equality of every pair of left and right expressions.
+    Join(JoinType, Vec<Instruction>, Vec<(Expr, Expr)>),
+    /// Replaces the dataset with the differences between the schema of every record batch and
+    /// the expected one, one row per kind of change with the `change`, `count` and `columns`
+    /// columns. Only valid as the first instruction, it is applied before the batches are
+    /// registered since batches with different schemas cannot share a table.
+    SchemaDrift(SchemaRef),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -18,11 +53,132 @@ pub enum AggregateType {
     Min,
     Max,
     Count,
+    CountDistinct,
+    StdDev,
+    /// Exact quantile, the inner value must be between 0 and 1.
+    Quantile(f64),
+    /// Approximate quantile computed with a t-digest, the inner value must be between 0 and 1.
+    ApproxQuantile(f64),
+    /// Serialized HyperLogLog sketch of `Utf8` values, the inner value is its precision.
+    Sketch(u8),
+    /// Union of serialized HyperLogLog sketches.
+    MergeSketch,
+}
+
+/// Aggregations over a pair of columns.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PairAggregateType {
+    /// Pearson correlation.
+    Correlation,
+    /// Sample covariance.
+    Covariance,
+}
+
+/// Upper boundaries of the buckets of a histogram, an implicit `+Inf` bucket is always added.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Buckets {
+    /// Explicit upper boundaries.
+    Explicit(Vec<f64>),
+    /// `count` boundaries starting at `start`, each one `width` greater than the previous one.
+    Linear {
+        start: f64,
+        width: f64,
+        count: usize,
+    },
+    /// `count` boundaries starting at `start`, each one `factor` times the previous one.
+    Exponential {
+        start: f64,
+        factor: f64,
+        count: usize,
+    },
+}
+
+impl Buckets {
+    /// Returns the sorted and deduplicated upper boundaries of the buckets.
+    pub fn boundaries(&self) -> Vec<f64> {
+        let mut boundaries: Vec<f64> = match self {
+            Buckets::Explicit(boundaries) => boundaries.clone(),
+            Buckets::Linear {
+                start,
+                width,
+                count,
+            } => (0..*count).map(|i| start + width * i as f64).collect(),
+            Buckets::Exponential {
+                start,
+                factor,
+                count,
+            } => (0..*count).map(|i| start * factor.powi(i as i32)).collect(),
+        };
+        boundaries.retain(|b| b.is_finite());
+        boundaries.sort_by(|a, b| a.total_cmp(b));
+        boundaries.dedup();
+        boundaries
+    }
+}
+
+/// Expected step between consecutive values of `BuiltInMetricsBuilder::sequence_gaps`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SequenceStep {
+    /// Step of an integer column.
+    Integer(i64),
+    /// Step of a timestamp column.
+    Duration(Duration),
+}
+
+/// Defines how `BuiltInMetricsBuilder::quantiles` computes the quantiles.
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuantileMode {
+    Exact,
+    Approximate,
+}
+
+impl Display for AggregateType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            AggregateType::Sum => "sum",
+            AggregateType::Avg => "avg",
+            AggregateType::Min => "min",
+            AggregateType::Max => "max",
+            AggregateType::Count => "count",
+            AggregateType::CountDistinct => "count_distinct",
+            AggregateType::StdDev => "stddev",
+            AggregateType::Quantile(quantile) => {
+                return write!(f, "{}", percentile_label(*quantile))
+            }
+            AggregateType::ApproxQuantile(quantile) => {
+                return write!(f, "approx_{}", percentile_label(*quantile))
+            }
+            AggregateType::Sketch(_) => "sketch",
+            AggregateType::MergeSketch => "merge_sketch",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl Display for PairAggregateType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            PairAggregateType::Correlation => "correlation",
+            PairAggregateType::Covariance => "covariance",
+        };
+        write!(f, "{}", str)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct ExprValue(pub String, pub Expr);
 
+/// A pair of expressions aggregated together under an alias, e.g. the columns of a correlation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairValue(pub String, pub Expr, pub Expr);
+
+/// A single metric row produced by `Instruction::Unpivot`, `value` is cast to `Float64`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricValue {
+    pub name: String,
+    pub tags: Expr,
+    pub value: Expr,
+}
 
 /// `TransformationBuilder` is a builder for creating custom data transformations.
 ///
@@ -37,7 +193,7 @@ pub struct ExprValue(pub String, pub Expr);
 ///     .group_by(vec!["category"])
 ///     .build();
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct TransformationBuilder {
     instructions: Vec<Instruction>,
 }
@@ -51,14 +207,14 @@ impl TransformationBuilder {
 
     pub fn select(mut self, columns: Vec<&str>) -> Self {
         self.instructions.push(Instruction::Select(
-            columns.iter().map(|&c| col(c)).collect(),
+            columns.iter().map(|&c| selected_col(c)).collect(),
         ));
         self
     }
 
     pub fn group_by(mut self, columns: Vec<&str>) -> Self {
         self.instructions.push(Instruction::GroupBy(
-            columns.iter().map(|&c| col(c)).collect(),
+            columns.iter().map(|&c| selected_col(c)).collect(),
         ));
         self
     }
@@ -68,12 +224,78 @@ impl TransformationBuilder {
             agg_type,
             columns
                 .iter()
-                .map(|&c| ExprValue(c.to_string(), col(c)))
+                .map(|&c| ExprValue(c.to_string(), nested_col(c)))
+                .collect(),
+        ));
+        self
+    }
+
+    /// Computes the aggregation per group of `group_by` columns and joins the result back
+    /// onto every row, so later instructions can compare each record with its group. Records
+    /// with null `group_by` values are compared with the group of null values.
+    pub fn join_aggregate(
+        mut self,
+        agg_type: AggregateType,
+        columns: Vec<&str>,
+        group_by: Vec<&str>,
+    ) -> Self {
+        self.instructions.push(Instruction::JoinAggregate(
+            group_by.iter().map(|&c| nested_col(c)).collect(),
+            columns
+                .iter()
+                .map(|&c| {
+                    (
+                        agg_type.clone(),
+                        ExprValue(format!("{}_{}", c, agg_type), nested_col(c)),
+                    )
+                })
+                .collect(),
+        ));
+        self
+    }
+
+    /// Aggregates each pair of columns, computed at the next group by like aggregations.
+    ///
+    /// Produces a `<first>_<second>_<type>` column per pair, e.g. `x_y_correlation`.
+    pub fn pair_aggregate(mut self, agg_type: PairAggregateType, pairs: Vec<(&str, &str)>) -> Self {
+        self.instructions.push(Instruction::PairAggregate(
+            agg_type.clone(),
+            pairs
+                .iter()
+                .map(|&(first, second)| {
+                    PairValue(
+                        format!("{}_{}_{}", first, second, agg_type),
+                        nested_col(first),
+                        nested_col(second),
+                    )
+                })
                 .collect(),
         ));
         self
     }
 
+    /// Adds a histogram of each column, computed at the next group by like aggregations.
+    ///
+    /// Produces `<column>_bucket_<i>`, `<column>_cumulative_<i>`, `<column>_sum` and
+    /// `<column>_count` columns, where `i` goes over the buckets plus the `+Inf` one.
+    pub fn histogram(mut self, columns: Vec<&str>, buckets: Buckets) -> Self {
+        let boundaries = buckets.boundaries();
+        for column in columns {
+            self.instructions.push(Instruction::Histogram(
+                ExprValue(column.to_string(), nested_col(column)),
+                boundaries.clone(),
+            ));
+        }
+        self
+    }
+
+    /// Expands every element of a list column into its own row, the other columns repeated.
+    pub fn unnest(mut self, column: &str) -> Self {
+        self.instructions
+            .push(Instruction::Unnest(column.to_string()));
+        self
+    }
+
     pub fn filter(mut self, condition: &str) -> Self {
         self.instructions
             .push(Instruction::Filter(condition.to_string()));
@@ -101,19 +323,39 @@ impl TransformationBuilder {
 /// ```ignore
 /// let transformation = BuiltInMetricsBuilder::new()
 ///     .count_null("value", None);
+///
+/// let hourly = BuiltInMetricsBuilder::new()
+///     .window("event_time", Duration::from_secs(3600))
+///     .count_null("value", None);
 /// ```
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct BuiltInMetricsBuilder {
     instructions: Vec<Instruction>,
+    window: Option<(String, Duration)>,
 }
 
 impl BuiltInMetricsBuilder {
     pub fn new() -> Self {
         Self {
             instructions: Vec::new(),
+            window: None,
         }
     }
 
+    /// Computes the metrics over tumbling windows instead of the whole dataset.
+    ///
+    /// One metric row is emitted per window, with `event_ts` set to the start of the window in
+    /// UTC. Naive event times are read as UTC.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the timestamp column holding the event time.
+    /// * `length` - The length of each window, windows are aligned to the unix epoch.
+    pub fn window(mut self, column: &str, length: Duration) -> Self {
+        self.window = Some((column.to_string(), length));
+        self
+    }
+
     /// Adds a count null transformation for the specified column.
     ///
     /// # Arguments
@@ -125,65 +367,2005 @@ impl BuiltInMetricsBuilder {
     ///
     /// A `Transformation` object representing the count null transformation.
     pub fn count_null(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        // counted instead of filtered, so windows without null values still get a 0 row
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Count,
+            vec![ExprValue(
+                "value".to_string(),
+                when(ident(column).is_null(), lit(1)).end().unwrap(),
+            )],
+        ));
         self.instructions
-            .push(Instruction::Select(vec![col(column)]));
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.completion_schema(&format!("{}_count_null", column), tags);
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a count non null transformation for the specified column.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the column to count non-null values in.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the count non null transformation.
+    pub fn count_non_null(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Count,
+            vec![ExprValue("value".to_string(), ident(column))],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.completion_schema(&format!("{}_count_non_null", column), tags);
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a completeness transformation for the specified column.
+    ///
+    /// The resulting `value` is the ratio of non-null records over the total number of
+    /// records as a `Float64`, or null when the dataset is empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the column to compute the completeness of.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the completeness transformation.
+    pub fn completeness(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Count,
+            vec![
+                ExprValue("non_null".to_string(), ident(column)),
+                ExprValue("total".to_string(), lit(1)),
+            ],
+        ));
         self.instructions
-            .push(Instruction::Filter(format!("{} is null", column)));
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        let mut columns = self.window_column();
+        columns.push(
+            (cast(col("non_null"), DataType::Float64)
+                / nullif(cast(col("total"), DataType::Float64), lit(0.0)))
+            .alias("value"),
+        );
+        self.instructions.push(Instruction::Select(columns));
+        self.completion_schema(&format!("{}_completeness", column), tags);
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a total count transformation, optionally grouped by a set of dimension columns.
+    ///
+    /// When dimensions are given one metric row is emitted per group, with the values of the
+    /// group appended to `tags` as `<dimension>=<value>`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dimensions` - Optional columns to group the records by.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the total count transformation.
+    pub fn count_total(
+        &mut self,
+        dimensions: Option<Vec<&str>>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
         self.instructions.push(Instruction::Aggregate(
             AggregateType::Count,
-            vec![ExprValue("value".to_string(), col(column))],
+            vec![ExprValue("value".to_string(), lit(1))],
+        ));
+        let dimensions = dimensions.unwrap_or_default();
+        self.instructions.push(Instruction::GroupBy(
+            self.with_window(dimensions.iter().map(|&c| selected_col(c)).collect()),
         ));
-        self.instructions.push(Instruction::GroupBy(Vec::new()));
-        self.completion_schema(column, tags);
+        let mut columns = self.window_column();
+        columns.extend([
+            col("value"),
+            lit("count_total").alias("metric_name"),
+            tags_expr_with_columns(&tags, &dimensions).alias("tags"),
+        ]);
+        self.instructions.push(Instruction::Select(columns));
+        self.completion_timestamps();
         Transformation {
             instructions: self.instructions.clone(),
         }
     }
 
-    /// Completes the schema for the transformation by adding additional columns.
+    /// Adds a count distinct transformation over a set of columns.
+    ///
+    /// Distinct tuples are counted across all the given columns, a tuple holding null values
+    /// is still counted as a distinct tuple. Null values of a single column are skipped as in
+    /// SQL's `COUNT(DISTINCT column)`, so `[1, null, 1]` counts 1 distinct value.
     ///
     /// # Arguments
     ///
-    /// * `column_name` - The name of the column being transformed.
+    /// * `columns` - The names of the columns whose distinct tuples are counted.
     /// * `tags` - Optional tags to include in the transformation.
-    fn completion_schema(&mut self, column_name: &str, tags: Option<Vec<&str>>) {
-        self.instructions.push(Instruction::Literal(
-            "metric_name".to_string(),
-            lit(format!("{}_count_null", column_name)),
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the count distinct transformation.
+    pub fn count_distinct(
+        &mut self,
+        columns: Vec<&str>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let expr = match columns.as_slice() {
+            [column] => nested_col(column),
+            _ => r#struct(columns.iter().map(|&c| nested_col(c)).collect()),
+        };
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::CountDistinct,
+            vec![ExprValue("value".to_string(), expr)],
         ));
-        self.instructions.push(Instruction::Literal(
-            "tags".to_string(),
-            lit(tags.unwrap_or(Vec::new()).join(",")),
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.completion_schema(&format!("{}_count_distinct", columns.join("_")), tags);
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds an approximate count distinct transformation over a set of columns.
+    ///
+    /// The estimate is computed from a HyperLogLog sketch, which is kept serialized in an
+    /// extra `sketch` column after `event_ts`. Unlike exact counts, sketches of different
+    /// executions can be merged later on with `BuiltInMetricsBuilder::merge_sketches`.
+    ///
+    /// # Arguments
+    ///
+    /// * `columns` - The names of the columns whose distinct tuples are counted.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the approximate count distinct transformation.
+    pub fn approx_count_distinct(
+        &mut self,
+        columns: Vec<&str>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        // same semantics as count_distinct, null values are skipped unless part of a tuple
+        let expr = match columns.as_slice() {
+            [column] => cast(nested_col(column), DataType::Utf8),
+            _ => concat_ws(
+                lit("\u{1f}"),
+                columns
+                    .iter()
+                    .map(|&c| coalesce(vec![cast(nested_col(c), DataType::Utf8), lit("\u{0}")]))
+                    .collect(),
+            ),
+        };
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Sketch(DEFAULT_PRECISION),
+            vec![ExprValue("sketch".to_string(), expr)],
         ));
         self.instructions
-            .push(Instruction::NewCol("system_ts".to_string(), current_time()));
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.instructions.push(Instruction::NewCol(
+            "value".to_string(),
+            hll_estimate(col("sketch")),
+        ));
+        self.completion_schema(
+            &format!("{}_approx_count_distinct", columns.join("_")),
+            tags,
+        );
+        self.instructions.push(Instruction::Select(
+            [
+                "value",
+                "metric_name",
+                "tags",
+                "system_ts",
+                "event_ts",
+                "sketch",
+            ]
+            .iter()
+            .map(|&c| col(c))
+            .collect(),
+        ));
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a transformation merging the sketches of previously computed metrics.
+    ///
+    /// The input is expected to hold the output of `BuiltInMetricsBuilder::approx_count_distinct`,
+    /// e.g. the hourly metrics of a day. One row is emitted per `metric_name` and `tags` with
+    /// the merged sketch and its estimate, rows without a sketch are skipped. When windowing,
+    /// the window is usually set on `event_ts` to roll the metrics up into daily or weekly ones.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the merge sketches transformation.
+    pub fn merge_sketches(&mut self) -> Transformation {
         self.instructions
-            .push(Instruction::NewCol("event_ts".to_string(), current_time()));
+            .push(Instruction::Filter("sketch IS NOT NULL".to_string()));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::MergeSketch,
+            vec![ExprValue("sketch".to_string(), col("sketch"))],
+        ));
+        self.instructions.push(Instruction::GroupBy(
+            self.with_window(vec![col("metric_name"), col("tags")]),
+        ));
+        self.instructions.push(Instruction::NewCol(
+            "value".to_string(),
+            hll_estimate(col("sketch")),
+        ));
+        self.completion_timestamps();
+        self.instructions.push(Instruction::Select(
+            [
+                "value",
+                "metric_name",
+                "tags",
+                "system_ts",
+                "event_ts",
+                "sketch",
+            ]
+            .iter()
+            .map(|&c| col(c))
+            .collect(),
+        ));
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
     }
-}
 
-// Define the Transformation struct to hold the list of Instructions
-#[derive(Debug, PartialEq, Default)]
-pub struct Transformation {
-    pub instructions: Vec<Instruction>,
-}
+    /// Adds a count duplicate transformation given a set of key columns.
+    ///
+    /// Emits `<keys>_duplicate_count`, the number of records whose key is repeated, and
+    /// `<keys>_duplicate_keys`, the number of distinct keys that repeat. When `sample` is set,
+    /// up to that many `<keys>_duplicate_sample` rows are added, per window when windowing, with
+    /// the repeat count of the most repeated keys as `value` and the key itself appended to
+    /// `tags`.
+    ///
+    /// # Arguments
+    ///
+    /// * `keys` - The names of the columns forming the key of a record.
+    /// * `sample` - Optional maximum number of duplicated keys to report.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the count duplicate transformation.
+    pub fn count_duplicate(
+        &mut self,
+        keys: Vec<&str>,
+        sample: Option<usize>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let metric_name = keys.join("_");
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Count,
+            vec![ExprValue("repeats".to_string(), lit(1))],
+        ));
+        self.instructions.push(Instruction::GroupBy(
+            self.with_window(keys.iter().map(|&c| selected_col(c)).collect()),
+        ));
 
-#[cfg(test)]
-mod tests {
-    use super::{AggregateType, Instruction, TransformationBuilder};
-    use datafusion::logical_expr::col;
+        // keys repeated once are counted as 0 rather than filtered, so windows without
+        // duplicates still get their rows
+        let is_duplicate = col("repeats").gt(lit(1));
+        let counts = vec![
+            Instruction::Aggregate(
+                AggregateType::Sum,
+                vec![ExprValue(
+                    "records".to_string(),
+                    when(is_duplicate.clone(), col("repeats"))
+                        .otherwise(lit(0))
+                        .unwrap(),
+                )],
+            ),
+            Instruction::Aggregate(
+                AggregateType::Count,
+                vec![ExprValue(
+                    "keys".to_string(),
+                    when(is_duplicate, lit(1)).end().unwrap(),
+                )],
+            ),
+            Instruction::GroupBy(self.window_column()),
+            Instruction::Unpivot(
+                self.window_column(),
+                vec![
+                    MetricValue {
+                        name: format!("{}_duplicate_count", metric_name),
+                        tags: tags_expr(&tags),
+                        value: coalesce(vec![col("records"), lit(0)]),
+                    },
+                    MetricValue {
+                        name: format!("{}_duplicate_keys", metric_name),
+                        tags: tags_expr(&tags),
+                        value: col("keys"),
+                    },
+                ],
+            ),
+        ];
+        match sample {
+            Some(limit) => {
+                self.instructions.push(Instruction::Union(vec![
+                    counts,
+                    vec![
+                        Instruction::Filter("repeats > 1".to_string()),
+                        self.sample_rank(
+                            [col("repeats").sort(false, false)]
+                                .into_iter()
+                                .chain(keys.iter().map(|&c| ident(c).sort(true, false)))
+                                .collect(),
+                        ),
+                        Instruction::Filter(format!("sample_rank <= {}", limit)),
+                        Instruction::Unpivot(
+                            self.window_column(),
+                            vec![MetricValue {
+                                name: format!("{}_duplicate_sample", metric_name),
+                                tags: tags_expr_with_columns(&tags, &keys),
+                                value: col("repeats"),
+                            }],
+                        ),
+                    ],
+                ]));
+            }
+            None => self.instructions.extend(counts),
+        }
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
 
-    #[test]
-    fn test_build_transformation() {
-        let builder = TransformationBuilder::new();
-        let transform = builder
-            .select(vec!["id", "value", "category"])
-            .aggregate(AggregateType::Count, vec!["value"])
-            .group_by(vec!["category"])
-            .build();
-        let expected_instruction =
-            Instruction::Select(vec![col("id"), col("value"), col("category")]);
+    /// Adds a top-k frequency and entropy transformation for the specified categorical column.
+    ///
+    /// Emits `<column>_top_count` and `<column>_top_share` rows for the `k` most frequent
+    /// values, with the value and its rank appended to `tags` as `value=<value>,rank=<rank>`,
+    /// plus `<column>_entropy`, the Shannon entropy in nats of the value distribution. Null
+    /// values are counted as a value of their own. The `%`, `,` and `=` characters of the value
+    /// are percent-encoded in `tags`, e.g. `value=x%2Cy` for `x,y`.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the categorical column.
+    /// * `k` - The number of most frequent values to emit.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the top-k transformation.
+    pub fn top_k(&mut self, column: &str, k: usize, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Count,
+            vec![ExprValue("frequency".to_string(), lit(1))],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(vec![ident(column)])));
+        self.instructions.push(Instruction::JoinAggregate(
+            self.window_column(),
+            vec![(
+                AggregateType::Sum,
+                ExprValue("total".to_string(), col("frequency")),
+            )],
+        ));
+        self.instructions.push(Instruction::NewCol(
+            "share".to_string(),
+            cast(col("frequency"), DataType::Float64) / cast(col("total"), DataType::Float64),
+        ));
+
+        // ties are ranked by value so the emitted values are stable across executions
+        let rank = row_number()
+            .partition_by(self.window_column())
+            .order_by(vec![
+                col("frequency").sort(false, false),
+                ident(column).sort(true, false),
+            ])
+            .build()
+            .unwrap();
+        let mut top_tags = tags
+            .clone()
+            .unwrap_or_default()
+            .iter()
+            .map(|&t| lit(t))
+            .collect::<Vec<Expr>>();
+        top_tags.push(concat(vec![lit("value="), tag_value(ident(column))]));
+        top_tags.push(concat(vec![
+            lit("rank="),
+            cast(col("top_rank"), DataType::Utf8),
+        ]));
+        self.instructions.push(Instruction::Union(vec![
+            vec![
+                Instruction::NewCol("top_rank".to_string(), rank),
+                Instruction::Filter(format!("top_rank <= {}", k)),
+                Instruction::Unpivot(
+                    self.window_column(),
+                    vec![
+                        MetricValue {
+                            name: format!("{}_top_count", column),
+                            tags: concat_ws(lit(","), top_tags.clone()),
+                            value: col("frequency"),
+                        },
+                        MetricValue {
+                            name: format!("{}_top_share", column),
+                            tags: concat_ws(lit(","), top_tags),
+                            value: col("share"),
+                        },
+                    ],
+                ),
+            ],
+            vec![
+                Instruction::Aggregate(
+                    AggregateType::Sum,
+                    vec![ExprValue(
+                        "entropy".to_string(),
+                        lit(0.0) - col("share") * ln(col("share")),
+                    )],
+                ),
+                Instruction::GroupBy(self.window_column()),
+                Instruction::Unpivot(
+                    self.window_column(),
+                    vec![MetricValue {
+                        name: format!("{}_entropy", column),
+                        tags: tags_expr(&tags),
+                        value: col("entropy"),
+                    }],
+                ),
+            ],
+        ]));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a correlation transformation for the given pairs of numeric columns.
+    ///
+    /// Emits one `<first>_<second>_correlation` row per pair with its Pearson correlation and,
+    /// when `covariance` is set, one `<first>_<second>_covariance` row with its sample
+    /// covariance. Records where any column of a pair is null are skipped for that pair. No
+    /// rows are emitted without pairs.
+    ///
+    /// # Arguments
+    ///
+    /// * `pairs` - The pairs of numeric columns.
+    /// * `covariance` - Whether the sample covariance of each pair is emitted too.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the correlation transformation.
+    pub fn correlation(
+        &mut self,
+        pairs: Vec<(&str, &str)>,
+        covariance: bool,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        if pairs.is_empty() {
+            // e.g. a dataset with less than two numeric columns, no metric rows are emitted
+            self.instructions
+                .push(Instruction::Unpivot(Vec::new(), Vec::new()));
+            self.completion_event_ts(now());
+            return Transformation {
+                instructions: self.instructions.clone(),
+            };
+        }
+        let mut agg_types = vec![PairAggregateType::Correlation];
+        if covariance {
+            agg_types.push(PairAggregateType::Covariance);
+        }
+        let mut values = Vec::new();
+        for agg_type in agg_types {
+            let mut aggregates = Vec::new();
+            for (i, &(first, second)) in pairs.iter().enumerate() {
+                let alias = format!("{}_{}", agg_type, i);
+                aggregates.push(PairValue(
+                    alias.clone(),
+                    cast(nested_col(first), DataType::Float64),
+                    cast(nested_col(second), DataType::Float64),
+                ));
+                values.push(MetricValue {
+                    name: format!("{}_{}_{}", first, second, agg_type),
+                    tags: tags_expr(&tags),
+                    value: col(alias),
+                });
+            }
+            self.instructions
+                .push(Instruction::PairAggregate(agg_type, aggregates));
+        }
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.instructions
+            .push(Instruction::Unpivot(self.window_column(), values));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a correlation transformation for every pair of numeric columns of `schema`.
+    ///
+    /// See `BuiltInMetricsBuilder::correlation`, pairs follow the order of the schema fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `schema` - The schema of the dataset.
+    /// * `covariance` - Whether the sample covariance of each pair is emitted too.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the correlation transformation.
+    pub fn correlation_all(
+        &mut self,
+        schema: &Schema,
+        covariance: bool,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let numeric: Vec<&str> = schema
+            .fields()
+            .iter()
+            .filter(|f| f.data_type().is_numeric())
+            .map(|f| f.name().as_str())
+            .collect();
+        let pairs = numeric
+            .iter()
+            .enumerate()
+            .flat_map(|(i, &first)| numeric[i + 1..].iter().map(move |&second| (first, second)))
+            .collect();
+        self.correlation(pairs, covariance, tags)
+    }
+
+    /// Adds a distribution drift transformation comparing a column against a reference dataset.
+    ///
+    /// Categorical columns are compared value by value, numeric ones over the given buckets.
+    /// Emits `<column>_psi`, the Population Stability Index, and `<column>_kl_divergence`, the
+    /// Kullback-Leibler divergence of the current distribution from the reference one. Numeric
+    /// columns also emit `<column>_ks`, the Kolmogorov-Smirnov statistic of their non-null
+    /// values. Null values are a bucket of their own and empty buckets are given a share of
+    /// 0.0001 so both metrics stay finite. The drift is computed over the whole dataset, the
+    /// window of the builder is not applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the column, present in both datasets.
+    /// * `reference` - The name of the reference table, registered with `MetricsManager::with_table`.
+    /// * `buckets` - The buckets of a numeric column, `None` for a categorical one.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the drift transformation.
+    pub fn drift(
+        &mut self,
+        column: &str,
+        reference: &str,
+        buckets: Option<Buckets>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let bucket = match &buckets {
+            Some(buckets) => {
+                let value = cast(ident(column), DataType::Float64);
+                let boundaries = buckets.boundaries();
+                let mut bucket = when(value.clone().is_null(), lit(-1_i64));
+                for (i, &boundary) in boundaries.iter().enumerate() {
+                    bucket = bucket.when(value.clone().lt_eq(lit(boundary)), lit(i as i64));
+                }
+                bucket.otherwise(lit(boundaries.len() as i64)).unwrap()
+            }
+            None => cast(ident(column), DataType::Utf8),
+        };
+        let share = |count: &str, total: &str| {
+            let share = cast(col(count), DataType::Float64) / cast(col(total), DataType::Float64);
+            when(col(count).eq(lit(0)), lit(0.0001))
+                .otherwise(share)
+                .unwrap()
+        };
+        // only the compared column is kept so both datasets can be stacked
+        let source = |current: bool| {
+            Instruction::Select(vec![selected_col(column), lit(current).alias("is_current")])
+        };
 
-        assert_eq!(transform.instructions.contains(&expected_instruction), true)
+        let mut branches = vec![vec![
+            Instruction::Union(vec![
+                vec![source(true)],
+                vec![Instruction::Table(reference.to_string()), source(false)],
+            ]),
+            Instruction::Select(vec![bucket.alias("bucket"), col("is_current")]),
+            Instruction::Aggregate(
+                AggregateType::Sum,
+                vec![
+                    ExprValue(
+                        "current".to_string(),
+                        when(col("is_current"), lit(1)).otherwise(lit(0)).unwrap(),
+                    ),
+                    ExprValue(
+                        "reference".to_string(),
+                        when(col("is_current"), lit(0)).otherwise(lit(1)).unwrap(),
+                    ),
+                ],
+            ),
+            Instruction::GroupBy(vec![col("bucket")]),
+            Instruction::JoinAggregate(
+                Vec::new(),
+                vec![
+                    (
+                        AggregateType::Sum,
+                        ExprValue("current_total".to_string(), col("current")),
+                    ),
+                    (
+                        AggregateType::Sum,
+                        ExprValue("reference_total".to_string(), col("reference")),
+                    ),
+                ],
+            ),
+            Instruction::NewCol("p".to_string(), share("current", "current_total")),
+            Instruction::NewCol("q".to_string(), share("reference", "reference_total")),
+            Instruction::Aggregate(
+                AggregateType::Sum,
+                vec![
+                    ExprValue(
+                        "psi".to_string(),
+                        (col("p") - col("q")) * ln(col("p") / col("q")),
+                    ),
+                    ExprValue(
+                        "kl_divergence".to_string(),
+                        col("p") * ln(col("p") / col("q")),
+                    ),
+                ],
+            ),
+            Instruction::GroupBy(Vec::new()),
+            Instruction::Unpivot(
+                Vec::new(),
+                vec![
+                    MetricValue {
+                        name: format!("{}_psi", column),
+                        tags: tags_expr(&tags),
+                        value: col("psi"),
+                    },
+                    MetricValue {
+                        name: format!("{}_kl_divergence", column),
+                        tags: tags_expr(&tags),
+                        value: col("kl_divergence"),
+                    },
+                ],
+            ),
+        ]];
+        if buckets.is_some() {
+            // the empirical distributions difference is accumulated over the sorted values,
+            // ties share the same range frame so they are accumulated together
+            let difference =
+                Expr::WindowFunction(WindowFunction::new(sum_udaf(), vec![col("weight")]))
+                    .order_by(vec![col("x").sort(true, false)])
+                    .build()
+                    .unwrap();
+            branches.push(vec![
+                Instruction::Union(vec![
+                    vec![source(true)],
+                    vec![Instruction::Table(reference.to_string()), source(false)],
+                ]),
+                Instruction::Select(vec![
+                    cast(ident(column), DataType::Float64).alias("x"),
+                    col("is_current"),
+                ]),
+                Instruction::Filter("x IS NOT NULL".to_string()),
+                Instruction::JoinAggregate(
+                    vec![col("is_current")],
+                    vec![(AggregateType::Count, ExprValue("n".to_string(), lit(1)))],
+                ),
+                Instruction::NewCol(
+                    "weight".to_string(),
+                    when(
+                        col("is_current"),
+                        lit(1.0) / cast(col("n"), DataType::Float64),
+                    )
+                    .otherwise(lit(-1.0) / cast(col("n"), DataType::Float64))
+                    .unwrap(),
+                ),
+                Instruction::NewCol("difference".to_string(), difference),
+                Instruction::Aggregate(
+                    AggregateType::Max,
+                    vec![ExprValue("ks".to_string(), abs(col("difference")))],
+                ),
+                Instruction::GroupBy(Vec::new()),
+                Instruction::Unpivot(
+                    Vec::new(),
+                    vec![MetricValue {
+                        name: format!("{}_ks", column),
+                        tags: tags_expr(&tags),
+                        value: col("ks"),
+                    }],
+                ),
+            ]);
+        }
+        self.instructions.push(Instruction::Union(branches));
+        self.completion_event_ts(now());
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a schema drift transformation comparing every record batch with an expected schema.
+    ///
+    /// Emits `schema_added_columns`, `schema_removed_columns`, `schema_type_changes` and
+    /// `schema_nullability_changes`, each change counted once across all the batches. The
+    /// changed columns are appended to `tags` as `columns=<changes>` separated by `|`, e.g.
+    /// `columns=id:Int32->Int64` for a type change or `columns=id:not_null->nullable` for a
+    /// nullability change. It must be the first transformation of the builder, execution fails
+    /// otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `expected` - The expected schema of the record batches.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the schema drift transformation.
+    pub fn schema_drift(&mut self, expected: Schema, tags: Option<Vec<&str>>) -> Transformation {
+        let mut changes_tags = tags
+            .unwrap_or_default()
+            .iter()
+            .map(|&t| lit(t))
+            .collect::<Vec<Expr>>();
+        changes_tags.push(concat(vec![lit("columns="), col("columns")]));
+        self.instructions
+            .push(Instruction::SchemaDrift(SchemaRef::new(expected)));
+        self.instructions.push(Instruction::Select(vec![
+            col("count").alias("value"),
+            concat(vec![lit("schema_"), col("change")]).alias("metric_name"),
+            concat_ws(lit(","), changes_tags).alias("tags"),
+        ]));
+        self.completion_event_ts(now());
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a referential integrity transformation checking the dataset keys against a parent.
+    ///
+    /// The dataset holds the child records and `parent` names the parent table, registered
+    /// with `MetricsManager::with_table`. Emits `<keys>_orphan_count`, the number of child
+    /// records whose key is missing from the parent, and `<keys>_orphan_ratio`, its share of the
+    /// child records. Records with a null key are not checked. When `sample` is set, up to that
+    /// many `<keys>_orphan_sample` rows are added, per window when windowing, with the number of
+    /// records of the most frequent orphan keys as `value` and the key itself appended to `tags`.
+    ///
+    /// # Arguments
+    ///
+    /// * `parent` - The name of the parent table.
+    /// * `keys` - The pairs of child and parent key columns.
+    /// * `sample` - Optional maximum number of orphan keys to emit.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the referential integrity transformation.
+    pub fn referential_integrity(
+        &mut self,
+        parent: &str,
+        keys: Vec<(&str, &str)>,
+        sample: Option<usize>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let child_keys: Vec<&str> = keys.iter().map(|&(child, _)| child).collect();
+        let metric_name = child_keys.join("_");
+        let parent_aliases: Vec<String> = (0..keys.len())
+            .map(|i| format!("__parent_key_{}", i))
+            .collect();
+
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(child_keys.iter().map(|&c| selected_col(c)).collect()),
+        ));
+        self.instructions.push(Instruction::Filter(
+            child_keys
+                .iter()
+                .map(|c| format!("\"{}\" IS NOT NULL", c))
+                .collect::<Vec<_>>()
+                .join(" AND "),
+        ));
+        // the parent keys are deduplicated so every child record is joined once at most
+        self.instructions.push(Instruction::Join(
+            JoinType::Left,
+            vec![
+                Instruction::Table(parent.to_string()),
+                Instruction::Aggregate(
+                    AggregateType::Count,
+                    vec![ExprValue("__parent_records".to_string(), lit(1))],
+                ),
+                Instruction::GroupBy(
+                    keys.iter()
+                        .zip(&parent_aliases)
+                        .map(|(&(_, p), alias)| nested_col(p).alias(alias))
+                        .collect(),
+                ),
+            ],
+            child_keys
+                .iter()
+                .zip(&parent_aliases)
+                .map(|(&c, alias)| (ident(c), col(alias)))
+                .collect(),
+        ));
+        self.instructions.push(Instruction::NewCol(
+            "is_orphan".to_string(),
+            when(col("__parent_records").is_null(), lit(1))
+                .otherwise(lit(0))
+                .unwrap(),
+        ));
+
+        let counts = vec![
+            Instruction::Aggregate(
+                AggregateType::Sum,
+                vec![ExprValue("orphans".to_string(), col("is_orphan"))],
+            ),
+            Instruction::Aggregate(
+                AggregateType::Count,
+                vec![ExprValue("records".to_string(), lit(1))],
+            ),
+            Instruction::GroupBy(self.with_window(Vec::new())),
+            Instruction::Unpivot(
+                self.window_column(),
+                vec![
+                    MetricValue {
+                        name: format!("{}_orphan_count", metric_name),
+                        tags: tags_expr(&tags),
+                        value: coalesce(vec![col("orphans"), lit(0)]),
+                    },
+                    MetricValue {
+                        name: format!("{}_orphan_ratio", metric_name),
+                        tags: tags_expr(&tags),
+                        value: cast(col("orphans"), DataType::Float64)
+                            / nullif(cast(col("records"), DataType::Float64), lit(0.0)),
+                    },
+                ],
+            ),
+        ];
+        match sample {
+            Some(limit) => {
+                self.instructions.push(Instruction::Union(vec![
+                    counts,
+                    vec![
+                        Instruction::Filter("is_orphan = 1".to_string()),
+                        Instruction::Aggregate(
+                            AggregateType::Count,
+                            vec![ExprValue("records".to_string(), lit(1))],
+                        ),
+                        Instruction::GroupBy(
+                            self.with_window(child_keys.iter().map(|&c| ident(c)).collect()),
+                        ),
+                        self.sample_rank(
+                            [col("records").sort(false, false)]
+                                .into_iter()
+                                .chain(child_keys.iter().map(|&c| ident(c).sort(true, false)))
+                                .collect(),
+                        ),
+                        Instruction::Filter(format!("sample_rank <= {}", limit)),
+                        Instruction::Unpivot(
+                            self.window_column(),
+                            vec![MetricValue {
+                                name: format!("{}_orphan_sample", metric_name),
+                                tags: tags_expr_with_columns(&tags, &child_keys),
+                                value: col("records"),
+                            }],
+                        ),
+                    ],
+                ]));
+            }
+            None => self.instructions.extend(counts),
+        }
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a sequence gaps transformation for the specified integer or timestamp column.
+    ///
+    /// Consecutive values are compared in the order of `order_by`, e.g. an ingestion offset, or
+    /// in the order of the column itself when it is not set. Emits `<column>_gap_count`, the
+    /// number of transitions greater than `step`, `<column>_gap_max`, the largest of those
+    /// transitions in the units of the column or in seconds for timestamps. When `order_by` is
+    /// set, `<column>_non_monotonic_count` is emitted as well, the number of transitions going
+    /// backwards, sorted values never do. Null values are skipped and, when windowing, values
+    /// are only compared within their window.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the integer or timestamp column.
+    /// * `step` - The expected step between consecutive values.
+    /// * `order_by` - Optional column holding the order the values arrived in.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the sequence gaps transformation.
+    pub fn sequence_gaps(
+        &mut self,
+        column: &str,
+        step: SequenceStep,
+        order_by: Option<&str>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        // integers are compared as Int64 so offsets or ids above 2^53 keep their precision
+        let (sequence, step) = match step {
+            SequenceStep::Integer(step) => (cast(nested_col(column), DataType::Int64), lit(step)),
+            SequenceStep::Duration(step) => (
+                cast(
+                    date_part(lit("epoch"), nested_col(column)),
+                    DataType::Float64,
+                ),
+                lit(step.as_secs_f64()),
+            ),
+        };
+        let mut columns = vec![sequence.alias("sequence")];
+        if let Some(order_by) = order_by {
+            columns.push(nested_col(order_by).alias("arrival"));
+        }
+        self.instructions
+            .push(Instruction::Select(self.with_event_time(columns)));
+        self.instructions
+            .push(Instruction::Filter("sequence IS NOT NULL".to_string()));
+        // the window is kept as a column so transitions never cross windows
+        if let Some(window_start) = self.with_window(Vec::new()).pop() {
+            self.instructions.push(Instruction::NewCol(
+                "window_start".to_string(),
+                window_start.unalias(),
+            ));
+        }
+        let previous = lag(col("sequence"), Some(1), None)
+            .partition_by(self.window_column())
+            .order_by(vec![
+                col(order_by.map_or("sequence", |_| "arrival")).sort(true, false)
+            ])
+            .build()
+            .unwrap();
+        self.instructions.push(Instruction::NewCol(
+            "transition".to_string(),
+            col("sequence") - previous,
+        ));
+        let is_gap = col("transition").gt(step);
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Sum,
+            vec![
+                ExprValue(
+                    "gaps".to_string(),
+                    when(is_gap.clone(), lit(1)).otherwise(lit(0)).unwrap(),
+                ),
+                ExprValue(
+                    "regressions".to_string(),
+                    when(col("transition").lt(lit(0)), lit(1))
+                        .otherwise(lit(0))
+                        .unwrap(),
+                ),
+            ],
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Max,
+            vec![ExprValue(
+                "largest_gap".to_string(),
+                when(is_gap, col("transition")).end().unwrap(),
+            )],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.window_column()));
+        let mut values = vec![
+            MetricValue {
+                name: format!("{}_gap_count", column),
+                tags: tags_expr(&tags),
+                value: coalesce(vec![col("gaps"), lit(0)]),
+            },
+            MetricValue {
+                name: format!("{}_gap_max", column),
+                tags: tags_expr(&tags),
+                value: coalesce(vec![cast(col("largest_gap"), DataType::Float64), lit(0.0)]),
+            },
+        ];
+        if order_by.is_some() {
+            values.push(MetricValue {
+                name: format!("{}_non_monotonic_count", column),
+                tags: tags_expr(&tags),
+                value: coalesce(vec![col("regressions"), lit(0)]),
+            });
+        }
+        self.instructions
+            .push(Instruction::Unpivot(self.window_column(), values));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a z-score transformation for the specified numeric column.
+    ///
+    /// The mean and standard deviation of the column are joined back onto every record to
+    /// compute its absolute z-score. Emits `<column>_zscore_outliers`, the number of records
+    /// whose z-score is above `threshold`, and `<column>_zscore_max`, the maximum z-score.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the numeric column.
+    /// * `threshold` - The absolute z-score above which a record is an outlier.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the z-score transformation.
+    pub fn zscore(
+        &mut self,
+        column: &str,
+        threshold: f64,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::JoinAggregate(
+            self.with_window(Vec::new()),
+            vec![
+                (
+                    AggregateType::Avg,
+                    ExprValue("mean".to_string(), ident(column)),
+                ),
+                (
+                    AggregateType::StdDev,
+                    ExprValue("stddev".to_string(), ident(column)),
+                ),
+            ],
+        ));
+        self.instructions.push(Instruction::NewCol(
+            "zscore".to_string(),
+            abs((cast(ident(column), DataType::Float64) - col("mean"))
+                / nullif(col("stddev"), lit(0.0))),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Sum,
+            vec![ExprValue(
+                "outliers".to_string(),
+                when(col("zscore").gt(lit(threshold)), lit(1))
+                    .otherwise(lit(0))
+                    .unwrap(),
+            )],
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Max,
+            vec![ExprValue("max_zscore".to_string(), col("zscore"))],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.instructions.push(Instruction::Unpivot(
+            self.window_column(),
+            vec![
+                MetricValue {
+                    name: format!("{}_zscore_outliers", column),
+                    tags: tags_expr(&tags),
+                    value: coalesce(vec![col("outliers"), lit(0)]),
+                },
+                MetricValue {
+                    name: format!("{}_zscore_max", column),
+                    tags: tags_expr(&tags),
+                    value: col("max_zscore"),
+                },
+            ],
+        ));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a quantiles transformation for the specified numeric column.
+    ///
+    /// Emits one `<column>_p<percentile>` row per quantile, e.g. `latency_p99` for `0.99`.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the numeric column.
+    /// * `quantiles` - The quantiles to compute, each between 0 and 1.
+    /// * `mode` - Whether quantiles are exact or approximated with a t-digest.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the quantiles transformation.
+    pub fn quantiles(
+        &mut self,
+        column: &str,
+        quantiles: Vec<f64>,
+        mode: QuantileMode,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        // a repeated quantile would emit the same metric name twice
+        let quantiles = quantiles
+            .into_iter()
+            .fold(Vec::new(), |mut unique, quantile| {
+                if !unique.contains(&quantile) {
+                    unique.push(quantile);
+                }
+                unique
+            });
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        for (i, &quantile) in quantiles.iter().enumerate() {
+            let agg_type = match mode {
+                QuantileMode::Exact => AggregateType::Quantile(quantile),
+                QuantileMode::Approximate => AggregateType::ApproxQuantile(quantile),
+            };
+            self.instructions.push(Instruction::Aggregate(
+                agg_type,
+                vec![ExprValue(format!("quantile_{}", i), ident(column))],
+            ));
+        }
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.instructions.push(Instruction::Unpivot(
+            self.window_column(),
+            quantiles
+                .iter()
+                .enumerate()
+                .map(|(i, &quantile)| MetricValue {
+                    name: format!("{}_{}", column, percentile_label(quantile)),
+                    tags: tags_expr(&tags),
+                    value: col(format!("quantile_{}", i)),
+                })
+                .collect(),
+        ));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a histogram transformation for the specified numeric column.
+    ///
+    /// Follows the Prometheus histogram semantics: `<column>_histogram_bucket` rows hold the
+    /// cumulative count of values lower or equal than the `le` tag, `<column>_histogram_sum`
+    /// and `<column>_histogram_count` hold the sum and count of the values. Additionally,
+    /// `<column>_histogram_bucket_count` rows hold the count of values within each bucket.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the numeric column.
+    /// * `buckets` - The upper boundaries of the buckets, `+Inf` is always added.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the histogram transformation.
+    pub fn histogram(
+        &mut self,
+        column: &str,
+        buckets: Buckets,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let boundaries = buckets.boundaries();
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::Histogram(
+            ExprValue("histogram".to_string(), ident(column)),
+            boundaries.clone(),
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+
+        let mut values = Vec::new();
+        for i in 0..=boundaries.len() {
+            let le = boundaries
+                .get(i)
+                .map_or("+Inf".to_string(), |b| b.to_string());
+            values.push(MetricValue {
+                name: format!("{}_histogram_bucket", column),
+                tags: tags_expr_with(&tags, &format!("le={}", le)),
+                value: col(format!("histogram_cumulative_{}", i)),
+            });
+            values.push(MetricValue {
+                name: format!("{}_histogram_bucket_count", column),
+                tags: tags_expr_with(&tags, &format!("le={}", le)),
+                value: col(format!("histogram_bucket_{}", i)),
+            });
+        }
+        values.push(MetricValue {
+            name: format!("{}_histogram_sum", column),
+            tags: tags_expr(&tags),
+            value: coalesce(vec![col("histogram_sum"), lit(0.0)]),
+        });
+        values.push(MetricValue {
+            name: format!("{}_histogram_count", column),
+            tags: tags_expr(&tags),
+            value: col("histogram_count"),
+        });
+        self.instructions
+            .push(Instruction::Unpivot(self.window_column(), values));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a numeric summary transformation for the specified numeric column.
+    ///
+    /// Emits `<column>_min`, `<column>_max` and `<column>_mean` over the non-null values.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the numeric column.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the numeric summary transformation.
+    pub fn numeric_summary(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions
+            .push(Instruction::Select(self.with_event_time(vec![
+                cast(nested_col(column), DataType::Float64).alias("number"),
+            ])));
+        for (agg_type, alias) in [
+            (AggregateType::Min, "min"),
+            (AggregateType::Max, "max"),
+            (AggregateType::Avg, "mean"),
+        ] {
+            self.instructions.push(Instruction::Aggregate(
+                agg_type,
+                vec![ExprValue(format!("number_{}", alias), col("number"))],
+            ));
+        }
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.instructions.push(Instruction::Unpivot(
+            self.window_column(),
+            ["min", "max", "mean"]
+                .iter()
+                .map(|agg| MetricValue {
+                    name: format!("{}_{}", column, agg),
+                    tags: tags_expr(&tags),
+                    value: col(format!("number_{}", agg)),
+                })
+                .collect(),
+        ));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a string lengths transformation for the specified `Utf8` or `LargeUtf8` column.
+    ///
+    /// Emits `<column>_length_min`, `<column>_length_max` and `<column>_length_avg`, lengths
+    /// are counted in characters and null values are ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the string column.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the string lengths transformation.
+    pub fn string_lengths(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![character_length(nested_col(column)).alias("length")]),
+        ));
+        for agg_type in [AggregateType::Min, AggregateType::Max, AggregateType::Avg] {
+            self.instructions.push(Instruction::Aggregate(
+                agg_type.clone(),
+                vec![ExprValue(format!("length_{}", agg_type), col("length"))],
+            ));
+        }
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.instructions.push(Instruction::Unpivot(
+            self.window_column(),
+            ["min", "max", "avg"]
+                .iter()
+                .map(|agg| MetricValue {
+                    name: format!("{}_length_{}", column, agg),
+                    tags: tags_expr(&tags),
+                    value: col(format!("length_{}", agg)),
+                })
+                .collect(),
+        ));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a list lengths transformation for the specified list or map column.
+    ///
+    /// Emits `<column>_length_min`, `<column>_length_max` and `<column>_length_avg` over the
+    /// number of elements of the non-null lists, or the number of entries of the maps.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the list or map column.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the list lengths transformation.
+    pub fn list_lengths(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            // empty lists have no cardinality, only null lists are skipped
+            self.with_event_time(vec![when(
+                nested_col(column).is_not_null(),
+                coalesce(vec![cardinality(nested_col(column)), lit(0_u64)]),
+            )
+            .end()
+            .unwrap()
+            .alias("length")]),
+        ));
+        for agg_type in [AggregateType::Min, AggregateType::Max, AggregateType::Avg] {
+            self.instructions.push(Instruction::Aggregate(
+                agg_type.clone(),
+                vec![ExprValue(format!("length_{}", agg_type), col("length"))],
+            ));
+        }
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.instructions.push(Instruction::Unpivot(
+            self.window_column(),
+            ["min", "max", "avg"]
+                .iter()
+                .map(|agg| MetricValue {
+                    name: format!("{}_length_{}", column, agg),
+                    tags: tags_expr(&tags),
+                    value: col(format!("length_{}", agg)),
+                })
+                .collect(),
+        ));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a count distinct keys transformation for the specified map column.
+    ///
+    /// Emits `<column>_key_count_distinct`, the number of distinct keys across all the maps.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the map column.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the count distinct keys transformation.
+    pub fn count_distinct_keys(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![map_keys(nested_col(column)).alias("map_key")]),
+        ));
+        self.instructions
+            .push(Instruction::Unnest("map_key".to_string()));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::CountDistinct,
+            vec![ExprValue("value".to_string(), col("map_key"))],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        self.completion_schema(&format!("{}_key_count_distinct", column), tags);
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a count blank transformation for the specified `Utf8` or `LargeUtf8` column.
+    ///
+    /// Counts the values that are empty or only made of whitespace, null values are not blank.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the string column.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the count blank transformation.
+    pub fn count_blank(&mut self, column: &str, tags: Option<Vec<&str>>) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Sum,
+            vec![ExprValue(
+                "blank".to_string(),
+                when(regexp_like(ident(column), lit(r"^\s*$"), None), lit(1))
+                    .otherwise(lit(0))
+                    .unwrap(),
+            )],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        let mut columns = self.window_column();
+        columns.push(coalesce(vec![col("blank"), lit(0)]).alias("value"));
+        self.instructions.push(Instruction::Select(columns));
+        self.completion_schema(&format!("{}_count_blank", column), tags);
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a pattern match ratio transformation for the specified `Utf8` or `LargeUtf8` column.
+    ///
+    /// The resulting `value` is the share of non-null values matching the regular expression
+    /// `pattern`, e.g. `^[A-Z]{2}$` for ISO country codes, or null when all values are null.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the string column.
+    /// * `pattern` - The regular expression the values should match.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the pattern match ratio transformation.
+    pub fn pattern_match_ratio(
+        &mut self,
+        column: &str,
+        pattern: &str,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Sum,
+            vec![ExprValue(
+                "matching".to_string(),
+                when(regexp_like(ident(column), lit(pattern), None), lit(1))
+                    .otherwise(lit(0))
+                    .unwrap(),
+            )],
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Count,
+            vec![ExprValue("non_null".to_string(), ident(column))],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        let mut columns = self.window_column();
+        columns.push(
+            (cast(col("matching"), DataType::Float64)
+                / nullif(cast(col("non_null"), DataType::Float64), lit(0.0)))
+            .alias("value"),
+        );
+        self.instructions.push(Instruction::Select(columns));
+        self.completion_schema(&format!("{}_pattern_match_ratio", column), tags);
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a freshness transformation for the specified timestamp column.
+    ///
+    /// Emits `<column>_newest`, the newest event time as seconds since the unix epoch,
+    /// `<column>_age_seconds`, the age of the newest event relative to the execution time, and
+    /// `<column>_lag_seconds_min`, `<column>_lag_seconds_avg` and `<column>_lag_seconds_max`,
+    /// the distribution of the processing time minus the event time of every record. The
+    /// processing time is read from `processed_at`, e.g. an ingestion timestamp, or is the
+    /// execution time when it is not set. Records without a processing time are skipped from the
+    /// lag. Unless windowing, `event_ts` holds the newest event time instead of the execution
+    /// time.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the timestamp column holding the event time.
+    /// * `processed_at` - Optional timestamp column holding the processing time of the records.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the freshness transformation.
+    pub fn freshness(
+        &mut self,
+        column: &str,
+        processed_at: Option<&str>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let now_epoch = date_part(lit("epoch"), now());
+        let processed_epoch = match processed_at {
+            Some(processed_at) => date_part(lit("epoch"), nested_col(processed_at)),
+            None => now_epoch.clone(),
+        };
+        self.instructions
+            .push(Instruction::Select(self.with_event_time(vec![
+                nested_col(column).alias("event_time"),
+                date_part(lit("epoch"), nested_col(column)).alias("event_epoch"),
+                processed_epoch.alias("processed_epoch"),
+            ])));
+        self.instructions.push(Instruction::NewCol(
+            "lag".to_string(),
+            col("processed_epoch") - col("event_epoch"),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Max,
+            vec![
+                ExprValue("newest_event_time".to_string(), col("event_time")),
+                ExprValue("newest".to_string(), col("event_epoch")),
+                ExprValue("lag_max".to_string(), col("lag")),
+            ],
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Min,
+            vec![ExprValue("lag_min".to_string(), col("lag"))],
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Avg,
+            vec![ExprValue("lag_avg".to_string(), col("lag"))],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+
+        let mut keep = self.window_column();
+        keep.push(col("newest_event_time"));
+        let mut values = vec![
+            MetricValue {
+                name: format!("{}_newest", column),
+                tags: tags_expr(&tags),
+                value: col("newest"),
+            },
+            MetricValue {
+                name: format!("{}_age_seconds", column),
+                tags: tags_expr(&tags),
+                value: now_epoch - col("newest"),
+            },
+        ];
+        values.extend(["min", "avg", "max"].iter().map(|agg| MetricValue {
+            name: format!("{}_lag_seconds_{}", column, agg),
+            tags: tags_expr(&tags),
+            value: col(format!("lag_{}", agg)),
+        }));
+        self.instructions.push(Instruction::Unpivot(keep, values));
+        match self.window {
+            Some(_) => self.completion_timestamps(),
+            None => self.completion_event_ts(cast(col("newest_event_time"), event_ts_type())),
+        }
+        self.instructions
+            .push(Instruction::DropCol("newest_event_time".to_string()));
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Adds a not allowed values transformation for the specified categorical column.
+    ///
+    /// Emits `<column>_not_allowed_count`, the number of values outside `allowed`, and
+    /// `<column>_not_allowed_ratio`, its share of the non-null values. Null values are never
+    /// counted as not allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the column.
+    /// * `allowed` - The allowed values.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the not allowed values transformation.
+    pub fn count_not_allowed<T: Literal>(
+        &mut self,
+        column: &str,
+        allowed: Vec<T>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let allowed = allowed.iter().map(|v| v.lit()).collect();
+        self.violations(
+            column,
+            ident(column).in_list(allowed, true),
+            "not_allowed",
+            tags,
+        )
+    }
+
+    /// Adds an out of range transformation for the specified numeric column.
+    ///
+    /// Emits `<column>_out_of_range_count`, the number of values outside the bounds, and
+    /// `<column>_out_of_range_ratio`, its share of the non-null values. Null values are never
+    /// counted as out of range.
+    ///
+    /// # Arguments
+    ///
+    /// * `column` - The name of the column.
+    /// * `lower` - The lower bound, either included, excluded or unbounded.
+    /// * `upper` - The upper bound, either included, excluded or unbounded.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the out of range transformation.
+    pub fn count_out_of_range<T: Literal>(
+        &mut self,
+        column: &str,
+        lower: Bound<T>,
+        upper: Bound<T>,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        let below = match lower {
+            Bound::Included(v) => Some(ident(column).lt(v.lit())),
+            Bound::Excluded(v) => Some(ident(column).lt_eq(v.lit())),
+            Bound::Unbounded => None,
+        };
+        let above = match upper {
+            Bound::Included(v) => Some(ident(column).gt(v.lit())),
+            Bound::Excluded(v) => Some(ident(column).gt_eq(v.lit())),
+            Bound::Unbounded => None,
+        };
+        let condition = match (below, above) {
+            (Some(below), Some(above)) => below.or(above),
+            (Some(condition), None) | (None, Some(condition)) => condition,
+            (None, None) => lit(false),
+        };
+        self.violations(column, condition, "out_of_range", tags)
+    }
+
+    /// Adds a profile of every column of `schema` as a single transformation.
+    ///
+    /// Every column gets `count_null`, primitive and string columns get `count_distinct`,
+    /// numeric columns get `numeric_summary`, string columns get `string_lengths`, list and
+    /// map columns get `list_lengths` and map columns get `count_distinct_keys`. The fields of
+    /// struct columns are profiled as well under their path, e.g. `payload.user.id`. All the
+    /// metrics are stacked in the standard layout with a `Float64` value.
+    ///
+    /// # Arguments
+    ///
+    /// * `schema` - The schema of the dataset.
+    /// * `tags` - Optional tags to include in the transformation.
+    ///
+    /// # Returns
+    ///
+    /// A `Transformation` object representing the profile transformation.
+    pub fn profile(&mut self, schema: &Schema, tags: Option<Vec<&str>>) -> Transformation {
+        // struct columns are profiled along with every one of their nested fields
+        let mut columns: Vec<(String, DataType)> = Vec::new();
+        let mut pending: Vec<(String, DataType)> = schema
+            .fields()
+            .iter()
+            .rev()
+            .map(|f| (f.name().to_string(), f.data_type().clone()))
+            .collect();
+        while let Some((path, data_type)) = pending.pop() {
+            if let DataType::Struct(fields) = &data_type {
+                pending.extend(
+                    fields
+                        .iter()
+                        .rev()
+                        .map(|f| (format!("{}.{}", path, f.name()), f.data_type().clone())),
+                );
+            }
+            columns.push((path, data_type));
+        }
+
+        let mut branches = Vec::new();
+        for (column, data_type) in &columns {
+            let column = column.as_str();
+            let is_string = matches!(data_type, DataType::Utf8 | DataType::LargeUtf8);
+            // every built-in starts from the dataset in its own branch
+            let mut transformations = vec![self.branch().count_null(column, tags.clone())];
+            if data_type.is_primitive() || is_string || data_type == &DataType::Boolean {
+                transformations.push(self.branch().count_distinct(vec![column], tags.clone()));
+            }
+            if data_type.is_numeric() {
+                transformations.push(self.branch().numeric_summary(column, tags.clone()));
+            }
+            if is_string {
+                transformations.push(self.branch().string_lengths(column, tags.clone()));
+            }
+            if matches!(
+                data_type,
+                DataType::List(_) | DataType::LargeList(_) | DataType::Map(_, _)
+            ) {
+                transformations.push(self.branch().list_lengths(column, tags.clone()));
+            }
+            if let DataType::Map(_, _) = data_type {
+                transformations.push(self.branch().count_distinct_keys(column, tags.clone()));
+            }
+            for transformation in transformations {
+                let mut instructions = transformation.instructions;
+                instructions.push(Instruction::Select(vec![
+                    cast(col("value"), DataType::Float64).alias("value"),
+                    col("metric_name"),
+                    col("tags"),
+                    col("system_ts"),
+                    col("event_ts"),
+                ]));
+                branches.push(instructions);
+            }
+        }
+        self.instructions.push(Instruction::Union(branches));
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Counts the non-null values of `column` meeting `condition` as both a count and a ratio.
+    fn violations(
+        &mut self,
+        column: &str,
+        condition: Expr,
+        metric: &str,
+        tags: Option<Vec<&str>>,
+    ) -> Transformation {
+        self.instructions.push(Instruction::Select(
+            self.with_event_time(vec![selected_col(column)]),
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Sum,
+            vec![ExprValue(
+                "violations".to_string(),
+                // the condition alone may hold for nulls, e.g. `NOT IN` an empty list
+                when(ident(column).is_not_null().and(condition), lit(1))
+                    .otherwise(lit(0))
+                    .unwrap(),
+            )],
+        ));
+        self.instructions.push(Instruction::Aggregate(
+            AggregateType::Count,
+            vec![ExprValue("non_null".to_string(), ident(column))],
+        ));
+        self.instructions
+            .push(Instruction::GroupBy(self.with_window(Vec::new())));
+        let violations = coalesce(vec![col("violations"), lit(0)]);
+        self.instructions.push(Instruction::Unpivot(
+            self.window_column(),
+            vec![
+                MetricValue {
+                    name: format!("{}_{}_count", column, metric),
+                    tags: tags_expr(&tags),
+                    value: violations.clone(),
+                },
+                MetricValue {
+                    name: format!("{}_{}_ratio", column, metric),
+                    tags: tags_expr(&tags),
+                    value: cast(violations, DataType::Float64)
+                        / nullif(cast(col("non_null"), DataType::Float64), lit(0.0)),
+                },
+            ],
+        ));
+        self.completion_timestamps();
+        Transformation {
+            instructions: self.instructions.clone(),
+        }
+    }
+
+    /// Completes the schema for the transformation by adding additional columns.
+    ///
+    /// # Arguments
+    ///
+    /// * `metric_name` - The name of the metric being computed.
+    /// * `tags` - Optional tags to include in the transformation.
+    fn completion_schema(&mut self, metric_name: &str, tags: Option<Vec<&str>>) {
+        self.instructions.push(Instruction::Literal(
+            "metric_name".to_string(),
+            lit(metric_name.to_string()),
+        ));
+        self.instructions
+            .push(Instruction::Literal("tags".to_string(), tags_expr(&tags)));
+        self.completion_timestamps();
+    }
+
+    /// Completes the schema of unpivoted metrics, which already carry `metric_name` and `tags`.
+    fn completion_timestamps(&mut self) {
+        match self.window {
+            Some(_) => {
+                // the window follows the timezone of the event time column, `event_ts` is
+                // always in UTC like `now()` so metrics of any kind share a schema
+                self.completion_event_ts(cast(col("window_start"), event_ts_type()));
+                self.instructions
+                    .push(Instruction::DropCol("window_start".to_string()));
+            }
+            None => self.completion_event_ts(now()),
+        }
+    }
+
+    /// Adds the `system_ts` execution time and the given `event_ts` columns.
+    fn completion_event_ts(&mut self, event_ts: Expr) {
+        self.instructions
+            .push(Instruction::NewCol("system_ts".to_string(), now()));
+        self.instructions
+            .push(Instruction::NewCol("event_ts".to_string(), event_ts));
+    }
+
+    /// Returns an empty builder sharing the window of this one.
+    fn branch(&self) -> Self {
+        Self {
+            instructions: Vec::new(),
+            window: self.window.clone(),
+        }
+    }
+
+    /// Adds the event time column to the columns selected from the dataset when windowing,
+    /// unless the metric already selects it.
+    fn with_event_time(&self, mut columns: Vec<Expr>) -> Vec<Expr> {
+        if let Some((column, _)) = &self.window {
+            let event_time = col(column);
+            let aliased = event_time.clone().alias(column);
+            if !columns.iter().any(|c| c == &event_time || c == &aliased) {
+                columns.push(event_time);
+            }
+        }
+        columns
+    }
+
+    /// Prepends the window the records belong to to the grouping columns when windowing.
+    fn with_window(&self, columns: Vec<Expr>) -> Vec<Expr> {
+        match &self.window {
+            Some((column, length)) => {
+                let stride = lit(ScalarValue::new_interval_mdn(
+                    0,
+                    0,
+                    length.as_nanos() as i64,
+                ));
+                let origin = lit(ScalarValue::TimestampNanosecond(Some(0), None));
+                let mut keys = vec![date_bin(stride, col(column), origin).alias("window_start")];
+                keys.extend(columns);
+                keys
+            }
+            None => columns,
+        }
+    }
+
+    /// Ranks the records in the given order as `sample_rank`, separately for each window when
+    /// windowing.
+    fn sample_rank(&self, order_by: Vec<Expr>) -> Instruction {
+        Instruction::NewCol(
+            "sample_rank".to_string(),
+            row_number()
+                .partition_by(self.window_column())
+                .order_by(order_by)
+                .build()
+                .unwrap(),
+        )
+    }
+
+    /// Returns the window start column of already grouped records when windowing.
+    fn window_column(&self) -> Vec<Expr> {
+        match self.window {
+            Some(_) => vec![col("window_start")],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Returns the data type of the `event_ts` column, the one of `now()`.
+fn event_ts_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Nanosecond, Some("+00:00".into()))
+}
+
+/// Returns the column at `path`, where dots address the fields of struct columns and the values
+/// of map columns, e.g. `payload.user.id`.
+///
+/// Nested fields selected by the built-in metrics are aliased as their full path, they are
+/// referenced with `ident` afterwards since `col` would read the dots as a table qualifier.
+pub fn nested_col(path: &str) -> Expr {
+    let mut fields = path.split('.');
+    let root = ident(fields.next().unwrap_or_default());
+    fields.fold(root, get_field)
+}
+
+/// Returns the column at `path` aliased as the path itself when nested.
+fn selected_col(path: &str) -> Expr {
+    match path.contains('.') {
+        true => nested_col(path).alias(path),
+        false => nested_col(path),
+    }
+}
+
+/// Renders the optional tags followed by an extra `key=value` tag.
+fn tags_expr_with(tags: &Option<Vec<&str>>, extra: &str) -> Expr {
+    let mut tags = tags.clone().unwrap_or_default();
+    tags.push(extra);
+    lit(tags.join(","))
+}
+
+/// Renders the optional tags followed by a `<column>=<value>` tag per column, the values are
+/// rendered with `tag_value`.
+fn tags_expr_with_columns(tags: &Option<Vec<&str>>, columns: &[&str]) -> Expr {
+    if columns.is_empty() {
+        return tags_expr(tags);
+    }
+    let mut tags = tags
+        .clone()
+        .unwrap_or_default()
+        .iter()
+        .map(|&t| lit(t))
+        .collect::<Vec<Expr>>();
+    tags.extend(
+        columns
+            .iter()
+            .map(|&c| concat(vec![lit(format!("{}=", c)), tag_value(ident(c))])),
+    );
+    concat_ws(lit(","), tags)
+}
+
+/// Renders `value` as the value of a tag, null values are rendered as `null`.
+///
+/// The `%`, `,` and `=` characters of the value are percent-encoded, e.g. `x,y` becomes `x%2Cy`,
+/// since the latter two delimit the tags.
+fn tag_value(value: Expr) -> Expr {
+    let value = coalesce(vec![cast(value, DataType::Utf8), lit("null")]);
+    [("%", "%25"), (",", "%2C"), ("=", "%3D")]
+        .iter()
+        .fold(value, |value, &(from, to)| {
+            replace(value, lit(from), lit(to))
+        })
+}
+
+/// Renders a quantile as a percentile label, e.g. `p50` for `0.5` or `p99_9` for `0.999`.
+///
+/// The decimal digits of the quantile are shifted rather than rounded, so different quantiles
+/// never share a label, e.g. `0.9991` is `p99_91`.
+fn percentile_label(quantile: f64) -> String {
+    let digits = quantile.to_string();
+    let (integer, fraction) = digits.split_once('.').unwrap_or((&digits, ""));
+    let fraction = format!("{:0<2}", fraction);
+    let (percent, decimals) = fraction.split_at(2);
+    let percent = format!("{}{}", integer, percent);
+    let percent = match percent.trim_start_matches('0') {
+        "" => "0",
+        percent => percent,
+    };
+    match decimals {
+        "" => format!("p{}", percent),
+        decimals => format!("p{}_{}", percent, decimals),
+    }
+}
+
+/// Renders the optional tags as the comma separated literal stored in the `tags` column.
+fn tags_expr(tags: &Option<Vec<&str>>) -> Expr {
+    lit(tags.clone().unwrap_or_default().join(","))
+}
+
+// Define the Transformation struct to hold the list of Instructions
+#[derive(Debug, PartialEq, Default)]
+pub struct Transformation {
+    pub instructions: Vec<Instruction>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{percentile_label, AggregateType, Instruction, TransformationBuilder};
+    use datafusion::logical_expr::col;
+
+    #[test]
+    fn test_build_transformation() {
+        let builder = TransformationBuilder::new();
+        let transform = builder
+            .select(vec!["id", "value", "category"])
+            .aggregate(AggregateType::Count, vec!["value"])
+            .group_by(vec!["category"])
+            .build();
+        let expected_instruction =
+            Instruction::Select(vec![col("id"), col("value"), col("category")]);
+
+        assert!(transform.instructions.contains(&expected_instruction))
+    }
+
+    #[test]
+    fn test_percentile_label() {
+        let labels: Vec<String> = [0.0, 0.05, 0.5, 0.999, 0.9991, 0.9999, 1.0]
+            .into_iter()
+            .map(percentile_label)
+            .collect();
+        assert_eq!(
+            labels,
+            vec!["p0", "p5", "p50", "p99_9", "p99_91", "p99_99", "p100"]
+        );
     }
 }
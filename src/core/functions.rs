@@ -0,0 +1,256 @@
+use std::any::Any;
+use std::sync::Arc;
+
+use arrow::array::{Array, ArrayRef, AsArray, Float64Array, ListArray};
+use arrow::datatypes::{DataType, Field, Float64Type};
+use datafusion::common::{DataFusionError, ScalarValue};
+use datafusion::logical_expr::{
+    create_udaf, create_udf, Accumulator, AggregateUDF, ColumnarValue, Expr, ScalarUDF,
+    ScalarUDFImpl, Signature, Volatility,
+};
+
+use crate::core::sketch::HyperLogLog;
+
+/// Returns the exact quantile of `expr` using linear interpolation between the closest ranks.
+///
+/// Every non-null value is kept in memory, prefer `approx_percentile_cont` on large datasets.
+pub fn quantile(expr: Expr, quantile: f64) -> Expr {
+    quantile_udaf(quantile).call(vec![expr])
+}
+
+fn quantile_udaf(quantile: f64) -> AggregateUDF {
+    create_udaf(
+        // the quantile is part of the name so different quantiles are never merged together
+        &format!("quantile_{}", quantile),
+        vec![DataType::Float64],
+        Arc::new(DataType::Float64),
+        Volatility::Immutable,
+        Arc::new(move |_| {
+            if !(0.0..=1.0).contains(&quantile) {
+                return Err(DataFusionError::Plan(format!(
+                    "Quantile must be between 0 and 1, got {}",
+                    quantile
+                )));
+            }
+            Ok(Box::new(QuantileAccumulator {
+                quantile,
+                values: Vec::new(),
+            }))
+        }),
+        Arc::new(vec![DataType::List(Arc::new(Field::new(
+            "item",
+            DataType::Float64,
+            true,
+        )))]),
+    )
+}
+
+#[derive(Debug)]
+struct QuantileAccumulator {
+    quantile: f64,
+    values: Vec<f64>,
+}
+
+impl Accumulator for QuantileAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<(), DataFusionError> {
+        self.values
+            .extend(values[0].as_primitive::<Float64Type>().iter().flatten());
+        Ok(())
+    }
+
+    fn evaluate(&mut self) -> Result<ScalarValue, DataFusionError> {
+        if self.values.is_empty() {
+            return Ok(ScalarValue::Float64(None));
+        }
+        self.values.sort_by(|a, b| a.total_cmp(b));
+        let position = self.quantile * (self.values.len() - 1) as f64;
+        let (lower, upper) = (position.floor() as usize, position.ceil() as usize);
+        let value = self.values[lower]
+            + (self.values[upper] - self.values[lower]) * (position - lower as f64);
+        Ok(ScalarValue::Float64(Some(value)))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self) + self.values.capacity() * std::mem::size_of::<f64>()
+    }
+
+    fn state(&mut self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        let values: ArrayRef = Arc::new(Float64Array::from(std::mem::take(&mut self.values)));
+        Ok(vec![ScalarValue::List(Arc::new(
+            datafusion::common::utils::array_into_list_array_nullable(values),
+        ))])
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<(), DataFusionError> {
+        for values in states[0].as_list::<i32>().iter().flatten() {
+            self.values
+                .extend(values.as_primitive::<Float64Type>().iter().flatten());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the serialized HyperLogLog sketch of the non-null values of the `Utf8` `expr`.
+pub fn hll_sketch(expr: Expr, precision: u8) -> Expr {
+    create_udaf(
+        // the precision is part of the name so sketches of different precisions are never merged
+        &format!("hll_sketch_{}", precision),
+        vec![DataType::Utf8],
+        Arc::new(DataType::Binary),
+        Volatility::Immutable,
+        Arc::new(move |_| {
+            Ok(Box::new(HyperLogLogAccumulator {
+                sketch: Some(HyperLogLog::new(precision)?),
+            }))
+        }),
+        Arc::new(vec![DataType::Binary]),
+    )
+    .call(vec![expr])
+}
+
+/// Returns the union of the serialized HyperLogLog sketches of `expr`, null sketches are skipped.
+pub fn hll_merge(expr: Expr) -> Expr {
+    create_udaf(
+        "hll_merge",
+        vec![DataType::Binary],
+        Arc::new(DataType::Binary),
+        Volatility::Immutable,
+        Arc::new(|_| Ok(Box::new(HyperLogLogAccumulator { sketch: None }))),
+        Arc::new(vec![DataType::Binary]),
+    )
+    .call(vec![expr])
+}
+
+/// Returns the estimated number of distinct values of the serialized HyperLogLog sketch `expr`.
+pub fn hll_estimate(expr: Expr) -> Expr {
+    create_udf(
+        "hll_estimate",
+        vec![DataType::Binary],
+        Arc::new(DataType::Float64),
+        Volatility::Immutable,
+        Arc::new(|args: &[ColumnarValue]| {
+            let sketches = ColumnarValue::values_to_arrays(args)?;
+            let estimates = sketches[0]
+                .as_binary::<i32>()
+                .iter()
+                .map(|bytes| {
+                    bytes
+                        .map(|bytes| HyperLogLog::from_bytes(bytes).map(|s| s.estimate()))
+                        .transpose()
+                })
+                .collect::<Result<Float64Array, DataFusionError>>()?;
+            Ok(ColumnarValue::Array(Arc::new(estimates)))
+        }),
+    )
+    .call(vec![expr])
+}
+
+/// Builds a sketch out of `Utf8` values when created with a sketch, or merges `Binary`
+/// sketches otherwise, taking the precision of the first one.
+#[derive(Debug)]
+struct HyperLogLogAccumulator {
+    sketch: Option<HyperLogLog>,
+}
+
+impl HyperLogLogAccumulator {
+    fn merge_sketches(&mut self, sketches: &ArrayRef) -> Result<(), DataFusionError> {
+        for bytes in sketches.as_binary::<i32>().iter().flatten() {
+            let other = HyperLogLog::from_bytes(bytes)?;
+            match &mut self.sketch {
+                Some(sketch) => sketch.merge(&other)?,
+                None => self.sketch = Some(other),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Accumulator for HyperLogLogAccumulator {
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<(), DataFusionError> {
+        match (&mut self.sketch, values[0].data_type()) {
+            (Some(sketch), DataType::Utf8) => {
+                for value in values[0].as_string::<i32>().iter().flatten() {
+                    sketch.insert(value.as_bytes());
+                }
+                Ok(())
+            }
+            _ => self.merge_sketches(&values[0]),
+        }
+    }
+
+    fn evaluate(&mut self) -> Result<ScalarValue, DataFusionError> {
+        Ok(ScalarValue::Binary(
+            self.sketch.as_ref().map(|s| s.to_bytes()),
+        ))
+    }
+
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self) + self.sketch.as_ref().map_or(0, |s| 1 << s.precision())
+    }
+
+    fn state(&mut self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        Ok(vec![self.evaluate()?])
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<(), DataFusionError> {
+        self.merge_sketches(&states[0])
+    }
+}
+
+/// Returns the keys of the map `expr` as a list, null maps give a null list.
+pub fn map_keys(expr: Expr) -> Expr {
+    ScalarUDF::new_from_impl(MapKeys {
+        signature: Signature::any(1, Volatility::Immutable),
+    })
+    .call(vec![expr])
+}
+
+#[derive(Debug)]
+struct MapKeys {
+    signature: Signature,
+}
+
+impl ScalarUDFImpl for MapKeys {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn name(&self) -> &str {
+        "map_keys"
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+
+    fn return_type(&self, arg_types: &[DataType]) -> Result<DataType, DataFusionError> {
+        match &arg_types[0] {
+            DataType::Map(entries, _) => match entries.data_type() {
+                DataType::Struct(fields) if !fields.is_empty() => Ok(DataType::List(Arc::new(
+                    Field::new("item", fields[0].data_type().clone(), false),
+                ))),
+                other => Err(DataFusionError::Plan(format!(
+                    "Map entries must be a struct of keys and values, got {}",
+                    other
+                ))),
+            },
+            other => Err(DataFusionError::Plan(format!(
+                "map_keys expects a Map argument, got {}",
+                other
+            ))),
+        }
+    }
+
+    fn invoke(&self, args: &[ColumnarValue]) -> Result<ColumnarValue, DataFusionError> {
+        let maps = ColumnarValue::values_to_arrays(args)?;
+        let maps = maps[0].as_map();
+        // the keys share the offsets of the map entries
+        let keys = ListArray::try_new(
+            Arc::new(Field::new("item", maps.keys().data_type().clone(), false)),
+            maps.offsets().clone(),
+            maps.keys().clone(),
+            maps.nulls().cloned(),
+        )?;
+        Ok(ColumnarValue::Array(Arc::new(keys)))
+    }
+}
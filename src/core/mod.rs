@@ -1,4 +1,6 @@
 pub mod computing;
 pub mod definition;
+pub mod functions;
 pub mod parser;
 pub mod publishing;
+pub mod sketch;
@@ -1,59 +1,103 @@
-use crate::core::definition::{AggregateType, ExprValue, Instruction};
-use datafusion::common::DataFusionError;
+use std::collections::HashMap;
+
+use crate::core::definition::{
+    AggregateType, ExprValue, Instruction, PairAggregateType, PairValue,
+};
+use crate::core::functions::{hll_merge, hll_sketch, quantile};
+use arrow::datatypes::DataType;
+use datafusion::common::{DataFusionError, ScalarValue};
 use datafusion::dataframe::DataFrame;
-use datafusion::functions_aggregate::expr_fn::{avg, count, max, min, sum};
-use datafusion::logical_expr::Expr;
+use datafusion::functions_aggregate::expr_fn::{
+    approx_percentile_cont, avg, corr, count, count_distinct, covar_samp, max, min, stddev, sum,
+};
+use datafusion::functions_nested::expr_fn::make_array;
+use datafusion::logical_expr::{binary_expr, cast, col, lit, when, Expr, JoinType, Operator};
 
+/// Builds the plan of `instructions` over `dataframe`, `tables` holds the extra named inputs.
 pub async fn parse(
-    instructions: &Vec<Instruction>,
+    instructions: &[Instruction],
+    dataframe: DataFrame,
+    tables: &HashMap<String, DataFrame>,
+) -> Result<DataFrame, DataFusionError> {
+    // a leading schema drift is applied by `execute` before the dataset is registered
+    let instructions = match instructions.split_first() {
+        Some((Instruction::SchemaDrift(_), rest)) => rest,
+        _ => instructions,
+    };
+    apply(instructions, dataframe, tables)
+}
+
+fn apply(
+    instructions: &[Instruction],
     mut dataframe: DataFrame,
+    tables: &HashMap<String, DataFrame>,
 ) -> Result<DataFrame, DataFusionError> {
-    for instruction in instructions {
+    for (position, instruction) in instructions.iter().enumerate() {
         match instruction {
             Instruction::Select(columns) => {
                 dataframe = dataframe.select(columns.to_vec())?;
             }
-            // aggregated instructions are tightly couple to group by
+            // aggregated instructions are tightly couple to group by, each group by takes the
+            // aggregations placed since the previous group by (or after it, for the last one)
             Instruction::GroupBy(columns) => {
-                if let Some(Instruction::Aggregate(_, _)) = instructions
-                    .iter()
-                    .find(|i| matches!(i, Instruction::Aggregate(_, _)))
-                {
-                    let agg_exprs: Vec<Expr> = instructions
+                let stage = aggregation_stage(instructions, position);
+                if stage.iter().any(|i| {
+                    matches!(
+                        i,
+                        Instruction::Aggregate(_, _)
+                            | Instruction::Histogram(_, _)
+                            | Instruction::PairAggregate(_, _)
+                    )
+                }) {
+                    let agg_exprs: Vec<Expr> = stage
                         .iter()
-                        .filter_map(|i| {
-                            if let Instruction::Aggregate(_, cols) = i {
-                                Some(
-                                    cols.iter()
-                                        .map(|ExprValue(alias, c)| match i {
-                                            Instruction::Aggregate(AggregateType::Sum, _) => {
-                                                sum(c.clone()).alias(alias)
-                                            }
-                                            Instruction::Aggregate(AggregateType::Avg, _) => {
-                                                avg(c.clone()).alias(alias)
-                                            }
-                                            Instruction::Aggregate(AggregateType::Min, _) => {
-                                                min(c.clone()).alias(alias)
-                                            }
-                                            Instruction::Aggregate(AggregateType::Max, _) => {
-                                                max(c.clone()).alias(alias)
-                                            }
-                                            Instruction::Aggregate(AggregateType::Count, _) => {
-                                                count(c.clone()).alias(alias)
-                                            }
-                                            _ => unreachable!(),
-                                        })
-                                        .collect::<Vec<Expr>>(),
-                                )
-                            } else {
-                                None
+                        .flat_map(|i| match i {
+                            Instruction::Aggregate(agg_type, cols) => {
+                                cols.iter().map(|c| aggregate_expr(agg_type, c)).collect()
                             }
+                            Instruction::Histogram(value, boundaries) => {
+                                histogram_exprs(value, boundaries)
+                            }
+                            Instruction::PairAggregate(agg_type, pairs) => pairs
+                                .iter()
+                                .map(|p| pair_aggregate_expr(agg_type, p))
+                                .collect(),
+                            _ => Vec::new(),
                         })
-                        .flatten()
                         .collect();
                     dataframe = dataframe.aggregate(columns.to_vec(), agg_exprs)?;
                 }
             }
+            // the aggregations are computed per key and joined back onto every row, null keys
+            // form a group of their own as in the aggregation itself
+            Instruction::JoinAggregate(keys, aggregates) => {
+                let key_aliases: Vec<String> = (0..keys.len())
+                    .map(|i| format!("__join_key_{}", i))
+                    .collect();
+                let aggregated = dataframe.clone().aggregate(
+                    keys.iter()
+                        .zip(&key_aliases)
+                        .map(|(key, alias)| key.clone().unalias().alias(alias))
+                        .collect(),
+                    aggregates
+                        .iter()
+                        .map(|(agg_type, value)| aggregate_expr(agg_type, value))
+                        .collect(),
+                )?;
+                dataframe = dataframe
+                    .join_on(
+                        aggregated,
+                        JoinType::Inner,
+                        keys.iter().zip(&key_aliases).map(|(key, alias)| {
+                            binary_expr(
+                                key.clone().unalias(),
+                                Operator::IsNotDistinctFrom,
+                                col(alias),
+                            )
+                        }),
+                    )?
+                    .drop_columns(&key_aliases.iter().map(|a| a.as_str()).collect::<Vec<_>>())?;
+            }
             Instruction::Filter(condition) => {
                 let filter_expr = dataframe.parse_sql_expr(condition)?;
                 dataframe = dataframe.filter(filter_expr)?;
@@ -61,9 +105,155 @@ pub async fn parse(
             Instruction::Literal(alias, expr) | Instruction::NewCol(alias, expr) => {
                 dataframe = dataframe.with_column(alias, expr.clone())?;
             }
+            Instruction::DropCol(column) => {
+                dataframe = dataframe.drop_columns(&[column.as_str()])?;
+            }
+            // every metric value becomes its own row, all of them sharing the kept columns. The
+            // values are gathered into lists unnested together, so the upstream plan runs once.
+            // Without metric values no rows are produced
+            Instruction::Unpivot(keep, values) if values.is_empty() => {
+                let mut exprs = keep.to_vec();
+                exprs.push(lit(ScalarValue::Float64(None)).alias("value"));
+                exprs.push(lit(ScalarValue::Utf8(None)).alias("metric_name"));
+                exprs.push(lit(ScalarValue::Utf8(None)).alias("tags"));
+                dataframe = dataframe.select(exprs)?.filter(lit(false))?;
+            }
+            Instruction::Unpivot(keep, values) => {
+                let mut exprs = keep.to_vec();
+                exprs.push(
+                    make_array(
+                        values
+                            .iter()
+                            .map(|v| cast(v.value.clone(), DataType::Float64))
+                            .collect(),
+                    )
+                    .alias("value"),
+                );
+                exprs.push(
+                    make_array(values.iter().map(|v| lit(v.name.clone())).collect())
+                        .alias("metric_name"),
+                );
+                exprs.push(
+                    make_array(values.iter().map(|v| v.tags.clone()).collect()).alias("tags"),
+                );
+                dataframe =
+                    dataframe
+                        .select(exprs)?
+                        .unnest_columns(&["value", "metric_name", "tags"])?;
+            }
+            Instruction::Union(branches) => {
+                let mut result: Option<DataFrame> = None;
+                for branch in branches {
+                    let branch = apply(branch, dataframe.clone(), tables)?;
+                    result = Some(match result {
+                        Some(result) => result.union(branch)?,
+                        None => branch,
+                    });
+                }
+                dataframe = result.ok_or_else(|| {
+                    DataFusionError::Plan("Union requires at least one branch".to_string())
+                })?;
+            }
+            Instruction::SchemaDrift(_) => {
+                return Err(DataFusionError::Plan(
+                    "Schema drift must be the first instruction of a transformation".to_string(),
+                ));
+            }
+            Instruction::Join(join_type, branch, on) => {
+                let right = apply(branch, dataframe.clone(), tables)?;
+                dataframe = dataframe.join_on(
+                    right,
+                    *join_type,
+                    on.iter()
+                        .map(|(left, right)| left.clone().eq(right.clone())),
+                )?;
+            }
+            Instruction::Unnest(column) => {
+                dataframe = dataframe.unnest_columns(&[column.as_str()])?;
+            }
+            Instruction::Table(name) => {
+                dataframe = tables.get(name).cloned().ok_or_else(|| {
+                    DataFusionError::Plan(format!("Table {} is not registered", name))
+                })?;
+            }
             _ => {}
         }
     }
 
     Ok(dataframe)
 }
+
+fn aggregate_expr(agg_type: &AggregateType, ExprValue(alias, c): &ExprValue) -> Expr {
+    match agg_type {
+        AggregateType::Sum => sum(c.clone()).alias(alias),
+        AggregateType::Avg => avg(c.clone()).alias(alias),
+        AggregateType::Min => min(c.clone()).alias(alias),
+        AggregateType::Max => max(c.clone()).alias(alias),
+        AggregateType::Count => count(c.clone()).alias(alias),
+        AggregateType::CountDistinct => count_distinct(c.clone()).alias(alias),
+        AggregateType::StdDev => stddev(c.clone()).alias(alias),
+        AggregateType::Quantile(q) => quantile(c.clone(), *q).alias(alias),
+        AggregateType::ApproxQuantile(q) => approx_percentile_cont(c.clone(), lit(*q)).alias(alias),
+        AggregateType::Sketch(precision) => hll_sketch(c.clone(), *precision).alias(alias),
+        AggregateType::MergeSketch => hll_merge(c.clone()).alias(alias),
+    }
+}
+
+fn pair_aggregate_expr(
+    agg_type: &PairAggregateType,
+    PairValue(alias, first, second): &PairValue,
+) -> Expr {
+    match agg_type {
+        PairAggregateType::Correlation => corr(first.clone(), second.clone()).alias(alias),
+        PairAggregateType::Covariance => covar_samp(first.clone(), second.clone()).alias(alias),
+    }
+}
+
+/// Returns the aggregations of a histogram over the upper `boundaries` of its buckets.
+///
+/// For `alias` and every bucket `i`, including the implicit `+Inf` one, this produces
+/// `<alias>_bucket_<i>` with the count of values in the bucket and `<alias>_cumulative_<i>`
+/// with the count of values lower or equal than its upper boundary, plus `<alias>_sum` and
+/// `<alias>_count`.
+fn histogram_exprs(ExprValue(alias, c): &ExprValue, boundaries: &[f64]) -> Vec<Expr> {
+    let value = cast(c.clone(), DataType::Float64);
+    let count_if =
+        |condition: Expr| sum(when(condition, lit(1_i64)).otherwise(lit(0_i64)).unwrap());
+    let mut exprs = Vec::new();
+    for i in 0..=boundaries.len() {
+        let lower = i
+            .checked_sub(1)
+            .map(|l| value.clone().gt(lit(boundaries[l])));
+        let upper = boundaries.get(i).map(|&b| value.clone().lt_eq(lit(b)));
+        let in_bucket = match (lower, upper.clone()) {
+            (Some(lower), Some(upper)) => lower.and(upper),
+            (Some(lower), None) => lower,
+            (None, Some(upper)) => upper,
+            (None, None) => value.clone().is_not_null(),
+        };
+        exprs.push(count_if(in_bucket).alias(format!("{}_bucket_{}", alias, i)));
+        exprs.push(
+            count_if(upper.unwrap_or_else(|| value.clone().is_not_null()))
+                .alias(format!("{}_cumulative_{}", alias, i)),
+        );
+    }
+    exprs.push(sum(value.clone()).alias(format!("{}_sum", alias)));
+    exprs.push(count(value).alias(format!("{}_count", alias)));
+    exprs
+}
+
+/// Returns the instructions sharing the aggregation stage of the group by at `position`.
+fn aggregation_stage(instructions: &[Instruction], position: usize) -> &[Instruction] {
+    let start = instructions[..position]
+        .iter()
+        .rposition(|i| matches!(i, Instruction::GroupBy(_)))
+        .map_or(0, |p| p + 1);
+    let is_last = !instructions[position + 1..]
+        .iter()
+        .any(|i| matches!(i, Instruction::GroupBy(_)));
+    if is_last {
+        &instructions[start..]
+    } else {
+        &instructions[start..position]
+    }
+}
@@ -0,0 +1,120 @@
+use datafusion::common::DataFusionError;
+
+/// Precision used by the built-in metrics, `2^14` registers for a standard error around 0.8%.
+pub const DEFAULT_PRECISION: u8 = 14;
+
+const MIN_PRECISION: u8 = 4;
+const MAX_PRECISION: u8 = 18;
+
+/// A HyperLogLog sketch estimating the number of distinct values inserted into it.
+///
+/// Values are hashed with a fixed 64-bit hash so sketches built by different executions, or
+/// different processes, can be merged together as long as they share the same precision.
+/// The serialized form is a single byte holding the precision followed by the registers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Creates an empty sketch with `2^precision` registers, `precision` must be between 4 and 18.
+    pub fn new(precision: u8) -> Result<Self, DataFusionError> {
+        if !(MIN_PRECISION..=MAX_PRECISION).contains(&precision) {
+            return Err(DataFusionError::Plan(format!(
+                "HyperLogLog precision must be between {} and {}, got {}",
+                MIN_PRECISION, MAX_PRECISION, precision
+            )));
+        }
+        Ok(Self {
+            precision,
+            registers: vec![0; 1 << precision],
+        })
+    }
+
+    /// Reads a sketch serialized with `HyperLogLog::to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DataFusionError> {
+        let (&precision, registers) = bytes
+            .split_first()
+            .ok_or_else(|| DataFusionError::Execution("HyperLogLog sketch is empty".to_string()))?;
+        let mut sketch = Self::new(precision)?;
+        if registers.len() != sketch.registers.len() {
+            return Err(DataFusionError::Execution(format!(
+                "HyperLogLog sketch of precision {} must hold {} registers, got {}",
+                precision,
+                sketch.registers.len(),
+                registers.len()
+            )));
+        }
+        sketch.registers.copy_from_slice(registers);
+        Ok(sketch)
+    }
+
+    /// Serializes the sketch, the precision first and then the registers.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.registers.len() + 1);
+        bytes.push(self.precision);
+        bytes.extend_from_slice(&self.registers);
+        bytes
+    }
+
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// Adds a value to the sketch.
+    pub fn insert(&mut self, value: &[u8]) {
+        let hash = hash(value);
+        let index = (hash >> (64 - self.precision)) as usize;
+        // the sentinel bit caps the rank when every remaining bit is zero
+        let remaining = (hash << self.precision) | (1 << (self.precision - 1));
+        let rank = remaining.leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    /// Merges `other` into this sketch, both sketches must share the same precision.
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), DataFusionError> {
+        if self.precision != other.precision {
+            return Err(DataFusionError::Execution(format!(
+                "Cannot merge HyperLogLog sketches of precision {} and {}",
+                self.precision, other.precision
+            )));
+        }
+        for (register, &other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(other);
+        }
+        Ok(())
+    }
+
+    /// Returns the estimated number of distinct values inserted into the sketch.
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / m),
+        };
+        let sum: f64 = self.registers.iter().map(|&r| 2f64.powi(-(r as i32))).sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&r| r == 0).count();
+        // linear counting is more accurate while many registers are still empty
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+/// FNV-1a followed by the murmur3 finalizer, stable across platforms and releases.
+fn hash(value: &[u8]) -> u64 {
+    let mut hash = value.iter().fold(0xcbf29ce484222325_u64, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
@@ -1,13 +1,22 @@
 use thiserror::Error;
-mod core;
-mod metrics;
-mod storage;
+pub mod core;
+pub mod metrics;
+pub mod storage;
+#[cfg(test)]
 mod test;
 
 #[derive(Error, Debug)]
 pub enum MetricError {
     #[error("DataFusionError: {0}")]
     DataFusionError(#[from] datafusion::error::DataFusionError),
-    #[error("Not supported storage backend: {0}")]
-    StorageBackendNotSupported(String),
+    #[error("ArrowError: {0}")]
+    ArrowError(#[from] arrow::error::ArrowError),
+    #[error("ParquetError: {0}")]
+    ParquetError(#[from] parquet::errors::ParquetError),
+    #[error("ObjectStoreError: {0}")]
+    ObjectStoreError(#[from] object_store::Error),
+    #[error("IoError: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Metric results are missing the column: {0}")]
+    MissingColumn(String),
 }
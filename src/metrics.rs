@@ -1,8 +1,12 @@
+use std::collections::HashMap;
+use std::io::Write;
+
 use arrow::array::RecordBatch;
+use arrow::datatypes::SchemaRef;
 
-use crate::core::computing::execute;
+use crate::core::computing::execute_with_schema;
 use crate::core::definition::Transformation;
-use crate::storage::StorageBackend;
+use crate::storage::{ipc, local_disk, s3, StorageBackend};
 use crate::MetricError;
 
 /// `MetricsManager` is responsible for managing and executing transformations on data record batches.
@@ -16,17 +20,12 @@ use crate::MetricError;
 ///             .unwrap()
 /// ```
 #[derive(Debug, Default)]
-struct MetricsManager {
+pub struct MetricsManager {
     transformation: Transformation,
     batches: Vec<RecordBatch>,
+    tables: HashMap<String, Vec<RecordBatch>>,
 }
 impl MetricsManager {
-    pub fn default() -> MetricsManager {
-        MetricsManager {
-            transformation: Transformation::default(),
-            batches: Vec::new(),
-        }
-    }
     pub fn transform(mut self, transformation: Transformation) -> MetricsManager {
         self.transformation = transformation;
         self
@@ -37,6 +36,12 @@ impl MetricsManager {
         self
     }
 
+    /// Registers an extra input under `name`, e.g. the reference dataset of the drift metrics.
+    pub fn with_table(mut self, name: &str, batches: Vec<RecordBatch>) -> MetricsManager {
+        self.tables.insert(name.to_string(), batches);
+        self
+    }
+
     /// Execution the instructions and publishes the results of the transformation to the specified storage backend.
     ///
     /// # Arguments
@@ -49,11 +54,10 @@ impl MetricsManager {
     ///
     /// # Errors
     ///
-    /// This function will return an error if the specified storage backend is not supported.
+    /// This function will return an error if the transformation fails or if the results cannot be
+    /// written to the storage backend.
     pub async fn publish(&self, storage_backend: StorageBackend) -> Result<(), MetricError> {
-        let result = execute(self.batches.clone(), &self.transformation)
-            .await
-            .unwrap();
+        let result = self.results().await?;
 
         match storage_backend {
             StorageBackend::Stdout => {
@@ -63,19 +67,60 @@ impl MetricsManager {
                 }
                 Ok(())
             }
-            _ => Err(MetricError::StorageBackendNotSupported(
-                storage_backend.to_string(),
-            )),
+            StorageBackend::LocalDisk(config) => {
+                local_disk::write(&config, &result)?;
+                Ok(())
+            }
+            StorageBackend::S3(config) => {
+                s3::write(&config.object_store()?, &config, &result).await?;
+                Ok(())
+            }
+            StorageBackend::Prometheus(registry) => registry.update(&result),
         }
     }
+
+    /// Executes the instructions and writes the results to `writer` as an Arrow IPC stream, e.g.
+    /// to hand them over to another process through a socket or a pipe.
+    ///
+    /// # Returns
+    ///
+    /// The `writer` once the stream is finished.
+    pub async fn publish_stream<W: Write>(&self, writer: W) -> Result<W, MetricError> {
+        let (schema, results) = self.results_with_schema().await?;
+        ipc::write_stream(writer, &schema, &results)
+    }
+
+    /// Executes the instructions and writes the results to `writer` as a single Arrow IPC file,
+    /// keeping every column unlike the partitioned files of `StorageBackend::LocalDisk`.
+    ///
+    /// # Returns
+    ///
+    /// The `writer` once the file is finished.
+    pub async fn publish_file<W: Write>(&self, writer: W) -> Result<W, MetricError> {
+        let (schema, results) = self.results_with_schema().await?;
+        ipc::write_file(writer, &schema, &results)
+    }
+
+    async fn results(&self) -> Result<Vec<RecordBatch>, MetricError> {
+        let (_, results) = self.results_with_schema().await?;
+        Ok(results)
+    }
+
+    async fn results_with_schema(&self) -> Result<(SchemaRef, Vec<RecordBatch>), MetricError> {
+        Ok(execute_with_schema(self.batches.clone(), &self.tables, &self.transformation).await?)
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::io::Cursor;
+
+    use arrow::ipc::reader::{FileReader, StreamReader};
+
     use crate::core::definition::{AggregateType, BuiltInMetricsBuilder, TransformationBuilder};
     use crate::metrics::MetricsManager;
-    use crate::storage::StorageBackend;
-    use crate::test::generate_dataset;
+    use crate::storage::{LocalDiskConfig, StorageBackend};
+    use crate::test::{column_as_f64, column_as_string, generate_dataset, temp_dir};
 
     #[tokio::test]
     async fn test_metrics_manager() {
@@ -104,4 +149,73 @@ mod test {
             .await
             .unwrap()
     }
+
+    #[tokio::test]
+    async fn test_completeness_metrics() {
+        let record_batch = generate_dataset();
+        let metrics = MetricsManager::default()
+            .transform(BuiltInMetricsBuilder::new().completeness("value", None))
+            .execute(vec![record_batch.unwrap()])
+            .results()
+            .await
+            .unwrap();
+        assert_eq!(
+            column_as_string(&metrics, "metric_name"),
+            vec![Some("value_completeness".to_string())]
+        );
+        assert_eq!(column_as_f64(&metrics, "value"), vec![Some(0.8)]);
+    }
+
+    #[tokio::test]
+    async fn test_publish_local_disk() {
+        let root = temp_dir("publish_local_disk");
+        let record_batch = generate_dataset();
+        MetricsManager::default()
+            .transform(BuiltInMetricsBuilder::new().count_null("value", None))
+            .execute(vec![record_batch.unwrap()])
+            .publish(StorageBackend::LocalDisk(LocalDiskConfig::new(&root)))
+            .await
+            .unwrap();
+        assert!(root.join("metric_name=value_count_null").is_dir());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_publish_file() {
+        let record_batch = generate_dataset();
+        let bytes = MetricsManager::default()
+            .transform(BuiltInMetricsBuilder::new().count_null("value", None))
+            .execute(vec![record_batch.unwrap()])
+            .publish_file(Vec::new())
+            .await
+            .unwrap();
+        let metrics = FileReader::try_new(Cursor::new(bytes), None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(metrics[0].num_columns(), 5);
+        assert_eq!(
+            column_as_string(&metrics, "metric_name"),
+            vec![Some("value_count_null".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_stream() {
+        let record_batch = generate_dataset();
+        let bytes = MetricsManager::default()
+            .transform(BuiltInMetricsBuilder::new().count_null("value", None))
+            .execute(vec![record_batch.unwrap()])
+            .publish_stream(Vec::new())
+            .await
+            .unwrap();
+        let metrics = StreamReader::try_new(Cursor::new(bytes), None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            column_as_string(&metrics, "metric_name"),
+            vec![Some("value_count_null".to_string())]
+        );
+    }
 }
@@ -0,0 +1,105 @@
+use std::io::Write;
+
+use arrow::array::RecordBatch;
+use arrow::datatypes::Schema;
+use arrow::ipc::writer::{FileWriter, StreamWriter};
+
+use crate::MetricError;
+
+/// Writes `batches` to `writer` in the Arrow IPC stream format, returning the writer.
+///
+/// Unlike the file based backends the results are not partitioned, `metric_name` is kept and
+/// every column has the exact type produced by the transformation. The stream always starts with
+/// `schema`, so readers get an empty stream rather than an error when there are no batches.
+pub fn write_stream<W: Write>(
+    writer: W,
+    schema: &Schema,
+    batches: &[RecordBatch],
+) -> Result<W, MetricError> {
+    let mut writer = StreamWriter::try_new(writer, schema)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+/// Writes `batches` to `writer` as a single Arrow IPC file, returning the writer.
+///
+/// As with `write_stream` the results are not partitioned, every column is kept with its exact
+/// type, unlike the IPC files of the local disk and S3 backends.
+pub fn write_file<W: Write>(
+    writer: W,
+    schema: &Schema,
+    batches: &[RecordBatch],
+) -> Result<W, MetricError> {
+    let mut writer = FileWriter::try_new(writer, schema)?;
+    for batch in batches {
+        writer.write(batch)?;
+    }
+    writer.finish()?;
+    Ok(writer.into_inner()?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+
+    use arrow::ipc::reader::{FileReader, StreamReader};
+
+    use crate::core::computing::execute_with_schema;
+    use crate::core::definition::BuiltInMetricsBuilder;
+    use crate::test::{assert_record_batches_equal, generate_dataset, generate_events_dataset};
+
+    use super::{write_file, write_stream};
+
+    #[tokio::test]
+    async fn test_write_stream() {
+        let (schema, result) = execute_with_schema(
+            vec![generate_dataset().unwrap()],
+            &HashMap::new(),
+            &BuiltInMetricsBuilder::new().completeness("value", None),
+        )
+        .await
+        .unwrap();
+
+        let bytes = write_stream(Vec::new(), &schema, &result).unwrap();
+        let read = StreamReader::try_new(Cursor::new(bytes), None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_record_batches_equal(read, result);
+
+        // without batches the stream still holds the schema, taken from the plan
+        let (schema, result) = execute_with_schema(
+            vec![generate_events_dataset().unwrap()],
+            &HashMap::new(),
+            &BuiltInMetricsBuilder::new().correlation(Vec::new(), false, None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.iter().map(|b| b.num_rows()).sum::<usize>(), 0);
+        let bytes = write_stream(Vec::new(), &schema, &[]).unwrap();
+        let reader = StreamReader::try_new(Cursor::new(bytes), None).unwrap();
+        assert_eq!(reader.schema().field(1).name(), "metric_name");
+        assert_eq!(reader.count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let (schema, result) = execute_with_schema(
+            vec![generate_dataset().unwrap()],
+            &HashMap::new(),
+            &BuiltInMetricsBuilder::new().count_null("value", None),
+        )
+        .await
+        .unwrap();
+
+        let bytes = write_file(Vec::new(), &schema, &result).unwrap();
+        let reader = FileReader::try_new(Cursor::new(bytes), None).unwrap();
+        assert_eq!(reader.schema(), schema);
+        let read = reader.collect::<Result<Vec<_>, _>>().unwrap();
+        assert_record_batches_equal(read, result);
+    }
+}
@@ -0,0 +1,295 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use arrow::array::RecordBatch;
+
+use crate::storage::{
+    part_created, part_name, partitions, FileFormat, Partition, Rollover, WriteMode,
+};
+use crate::MetricError;
+
+/// Configuration of `StorageBackend::LocalDisk`.
+///
+/// Metric results are written under `root`, partitioned Hive-style as
+/// `metric_name=<name>/date=<YYYY-MM-DD>/part-*.<extension>` by the date of `event_ts`.
+///
+/// Parquet files are never reopened, every publish adds a new file. CSV and NDJSON files are
+/// appended to when a `rollover` is set: publishes extend the newest file of the partition until
+/// it reaches the rollover size or age, then a new file is started. Without a rollover every
+/// publish adds a new file whatever the format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalDiskConfig {
+    pub root: PathBuf,
+    pub format: FileFormat,
+    pub mode: WriteMode,
+    pub rollover: Option<Rollover>,
+}
+
+impl LocalDiskConfig {
+    /// Creates a configuration appending Parquet files under `root`.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            format: FileFormat::default(),
+            mode: WriteMode::default(),
+            rollover: None,
+        }
+    }
+
+    pub fn with_format(mut self, format: FileFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: WriteMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_rollover(mut self, rollover: Rollover) -> Self {
+        self.rollover = Some(rollover);
+        self
+    }
+}
+
+/// Writes `batches` under the root of `config`, returning the written files.
+///
+/// New files are written under a hidden name and renamed once complete, and their names are
+/// unique, so readers listing the partitions never see partial files and runs publishing
+/// concurrently to the same root never overwrite each other. Appends are issued as a single
+/// write on a file opened in append mode.
+pub fn write(
+    config: &LocalDiskConfig,
+    batches: &[RecordBatch],
+) -> Result<Vec<PathBuf>, MetricError> {
+    let extension = config.format.extension();
+    let mut files = Vec::new();
+    for Partition { path, batch } in partitions(batches)? {
+        let directory = config.root.join(path);
+        fs::create_dir_all(&directory)?;
+        let existing = part_files(&directory, extension)?;
+        let file = match (config.mode, active_file(config, &existing)?) {
+            (WriteMode::Append, Some(active)) => {
+                let mut file = OpenOptions::new().append(true).open(active)?;
+                file.write_all(&config.format.encode(&batch, false)?)?;
+                active.clone()
+            }
+            _ => {
+                let name = part_name(extension);
+                let staging = directory.join(format!(".{}.tmp", name));
+                fs::write(&staging, config.format.encode(&batch, true)?)?;
+                let file = directory.join(name);
+                fs::rename(&staging, &file)?;
+                file
+            }
+        };
+        // replaced files are removed once the new one is in place, the partition is never empty
+        if config.mode == WriteMode::Overwrite {
+            for old in existing {
+                fs::remove_file(old)?;
+            }
+        }
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Returns the part files of `directory` with `extension`, oldest first.
+fn part_files(directory: &Path, extension: &str) -> Result<Vec<PathBuf>, MetricError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        let is_part = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with("part-") && n.ends_with(&format!(".{}", extension)));
+        if is_part {
+            files.push(path);
+        }
+    }
+    files.sort_by_key(|path| (created(path), path.clone()));
+    Ok(files)
+}
+
+/// Returns the newest of the `files` when it can still be appended to.
+fn active_file<'a>(
+    config: &LocalDiskConfig,
+    files: &'a [PathBuf],
+) -> Result<Option<&'a PathBuf>, MetricError> {
+    let (Some(rollover), Some(newest)) = (config.rollover, files.last()) else {
+        return Ok(None);
+    };
+    if !config.format.is_appendable() {
+        return Ok(None);
+    }
+    let rolled = match rollover {
+        Rollover::Size(bytes) => fs::metadata(newest)?.len() >= bytes,
+        // a file of unknown age is rolled over
+        Rollover::Time(age) => match created(newest)
+            .and_then(|created| SystemTime::now().duration_since(created).ok())
+        {
+            Some(elapsed) => elapsed >= age,
+            None => true,
+        },
+    };
+    Ok((!rolled).then_some(newest))
+}
+
+fn created(path: &Path) -> Option<SystemTime> {
+    part_created(path.file_name()?.to_str()?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::datatypes::DataType;
+    use arrow::ipc::reader::FileReader;
+    use datafusion::datasource::file_format::parquet::ParquetFormat;
+    use datafusion::datasource::listing::ListingOptions;
+    use datafusion::prelude::SessionContext;
+
+    use crate::core::computing::execute;
+    use crate::core::definition::BuiltInMetricsBuilder;
+    use crate::test::{column_as_f64, column_as_string, generate_dataset, temp_dir};
+
+    use crate::storage::{FileFormat, Rollover, WriteMode};
+
+    use super::{write, LocalDiskConfig};
+
+    #[tokio::test]
+    async fn test_write_partitioned_parquet() {
+        let root = temp_dir("local_disk");
+        let config = LocalDiskConfig::new(&root);
+        let result = execute(
+            vec![generate_dataset().unwrap()],
+            &BuiltInMetricsBuilder::new().completeness("value", None),
+        )
+        .await
+        .unwrap();
+
+        // two runs publishing to the same root append their own files
+        let first = write(&config, &result).unwrap();
+        let second = write(&config, &result).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_ne!(first, second);
+        let relative = first[0].strip_prefix(&root).unwrap().to_str().unwrap();
+        assert!(relative.starts_with("metric_name=value_completeness/date="));
+        assert!(relative.ends_with(".parquet"));
+
+        let ctx = SessionContext::new();
+        let options = ListingOptions::new(Arc::new(ParquetFormat::default()))
+            .with_file_extension(".parquet")
+            .with_table_partition_cols(vec![
+                ("metric_name".to_string(), DataType::Utf8),
+                ("date".to_string(), DataType::Utf8),
+            ]);
+        ctx.register_listing_table("metrics", root.to_str().unwrap(), options, None, None)
+            .await
+            .unwrap();
+        let metrics = ctx
+            .sql("select value, metric_name from metrics")
+            .await
+            .unwrap()
+            .collect()
+            .await
+            .unwrap();
+
+        assert_eq!(column_as_f64(&metrics, "value"), vec![Some(0.8), Some(0.8)]);
+        assert_eq!(
+            column_as_string(&metrics, "metric_name"),
+            vec![
+                Some("value_completeness".to_string()),
+                Some("value_completeness".to_string())
+            ]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_append_csv_with_rollover() {
+        let root = temp_dir("local_disk_csv");
+        let config = LocalDiskConfig::new(&root)
+            .with_format(FileFormat::Csv { header: true })
+            .with_rollover(Rollover::Size(1024));
+        let result = execute(
+            vec![generate_dataset().unwrap()],
+            &BuiltInMetricsBuilder::new().count_null("value", None),
+        )
+        .await
+        .unwrap();
+
+        let first = write(&config, &result).unwrap();
+        let second = write(&config, &result).unwrap();
+        assert_eq!(first, second);
+        let content = std::fs::read_to_string(&first[0]).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "value,tags,system_ts,event_ts");
+        assert!(lines[1].starts_with("1,,"));
+
+        // the file is full once it reaches the rollover size
+        let config = config.with_rollover(Rollover::Size(content.len() as u64));
+        let third = write(&config, &result).unwrap();
+        assert_ne!(first, third);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_ndjson() {
+        let root = temp_dir("local_disk_ndjson");
+        let config = LocalDiskConfig::new(&root)
+            .with_format(FileFormat::NdJson)
+            .with_mode(WriteMode::Overwrite);
+        let result = execute(
+            vec![generate_dataset().unwrap()],
+            &BuiltInMetricsBuilder::new().count_null("value", None),
+        )
+        .await
+        .unwrap();
+
+        let first = write(&config, &result).unwrap();
+        let second = write(&config, &result).unwrap();
+        assert!(!first[0].exists());
+        let directory = second[0].parent().unwrap();
+        assert_eq!(std::fs::read_dir(directory).unwrap().count(), 1);
+        let content = std::fs::read_to_string(&second[0]).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(content.starts_with("{\"value\":1,\"tags\":\"\","));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_arrow_ipc() {
+        let root = temp_dir("local_disk_ipc");
+        let config = LocalDiskConfig::new(&root)
+            .with_format(FileFormat::ArrowIpc)
+            .with_rollover(Rollover::Size(u64::MAX));
+        let result = execute(
+            vec![generate_dataset().unwrap()],
+            &BuiltInMetricsBuilder::new().count_null("value", None),
+        )
+        .await
+        .unwrap();
+
+        // IPC files are never appended to, whatever the rollover
+        let first = write(&config, &result).unwrap();
+        let second = write(&config, &result).unwrap();
+        assert_ne!(first, second);
+        assert!(first[0].to_str().unwrap().ends_with(".arrow"));
+
+        let file = std::fs::File::open(&first[0]).unwrap();
+        let metrics = FileReader::try_new(file, None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            metrics[0].schema(),
+            result[0].schema().project(&[0, 2, 3, 4]).unwrap().into()
+        );
+        assert_eq!(column_as_f64(&metrics, "value"), vec![Some(1.0)]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
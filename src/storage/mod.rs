@@ -1,19 +1,228 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use arrow::array::{ArrayRef, AsArray, RecordBatch, UInt32Array};
+use arrow::compute::{cast, concat_batches, take_record_batch};
+use arrow::csv::WriterBuilder;
+use arrow::datatypes::DataType;
+use arrow::ipc::writer::FileWriter;
+use arrow::json::LineDelimitedWriter;
+use parquet::arrow::ArrowWriter;
+
+use crate::MetricError;
+
+pub mod ipc;
+pub mod local_disk;
+pub mod prometheus;
+pub mod s3;
+
+pub use local_disk::LocalDiskConfig;
+pub use prometheus::{ExpositionFormat, MetricsRegistry, MetricsServer};
+pub use s3::{S3Config, S3Credentials};
 
 #[derive(Debug, PartialEq)]
 pub enum StorageBackend {
     Stdout,
-    LocalDisk,
-    S3,
+    LocalDisk(LocalDiskConfig),
+    S3(S3Config),
+    /// Keeps the latest values in the registry, to be scraped through a `MetricsServer`.
+    Prometheus(MetricsRegistry),
 }
 
 impl Display for StorageBackend {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let str = match self {
             StorageBackend::Stdout => "stdout".to_string(),
-            StorageBackend::LocalDisk => "local_disk".to_string(),
-            StorageBackend::S3 => "s3".to_string(),
+            StorageBackend::LocalDisk(_) => "local_disk".to_string(),
+            StorageBackend::S3(_) => "s3".to_string(),
+            StorageBackend::Prometheus(_) => "prometheus".to_string(),
         };
         write!(f, "{}", str)
     }
 }
+
+/// File format of the metric results written by the file based backends.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FileFormat {
+    #[default]
+    Parquet,
+    /// Comma separated values, with a header line at the start of every file when `header` is set.
+    Csv { header: bool },
+    /// Newline-delimited JSON, one object per metric.
+    NdJson,
+    /// Arrow IPC files, keeping the exact types of the metric results.
+    ArrowIpc,
+}
+
+impl FileFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FileFormat::Parquet => "parquet",
+            FileFormat::Csv { .. } => "csv",
+            FileFormat::NdJson => "json",
+            FileFormat::ArrowIpc => "arrow",
+        }
+    }
+
+    /// Whether files of this format can be extended by appending more encoded batches.
+    pub(crate) fn is_appendable(&self) -> bool {
+        !matches!(self, FileFormat::Parquet | FileFormat::ArrowIpc)
+    }
+
+    /// Encodes `batch`, `new_file` tells whether it starts a file or is appended to one.
+    pub(crate) fn encode(
+        &self,
+        batch: &RecordBatch,
+        new_file: bool,
+    ) -> Result<Vec<u8>, MetricError> {
+        let mut bytes = Vec::new();
+        match self {
+            FileFormat::Parquet => {
+                let mut writer = ArrowWriter::try_new(&mut bytes, batch.schema(), None)?;
+                writer.write(batch)?;
+                writer.close()?;
+            }
+            FileFormat::Csv { header } => {
+                WriterBuilder::new()
+                    .with_header(*header && new_file)
+                    .build(&mut bytes)
+                    .write(batch)?;
+            }
+            FileFormat::NdJson => {
+                let mut writer = LineDelimitedWriter::new(&mut bytes);
+                writer.write(batch)?;
+                writer.finish()?;
+            }
+            FileFormat::ArrowIpc => {
+                let mut writer = FileWriter::try_new(&mut bytes, &batch.schema())?;
+                writer.write(batch)?;
+                writer.finish()?;
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// How the file based backends treat the files already stored in a partition.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WriteMode {
+    /// Existing files are kept and the new metrics are added next to them.
+    #[default]
+    Append,
+    /// The files of every partition being written are replaced, other partitions are kept.
+    Overwrite,
+}
+
+/// When appendable files stop receiving metrics and a new file is started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rollover {
+    /// Once the file holds at least this many bytes.
+    Size(u64),
+    /// Once the file is older than this.
+    Time(Duration),
+}
+
+/// Value of the partitions whose column is null or empty, as named by Hive.
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// The metric results sharing a `metric_name` and an `event_ts` date.
+#[derive(Debug)]
+pub(crate) struct Partition {
+    /// Hive-style directory of the partition, e.g. `metric_name=value_count_null/date=2024-07-01`.
+    pub path: String,
+    /// The rows of the partition, without the `metric_name` column since the path holds it.
+    pub batch: RecordBatch,
+}
+
+/// Splits the metric results into partitions by `metric_name` and by the date of `event_ts`.
+pub(crate) fn partitions(batches: &[RecordBatch]) -> Result<Vec<Partition>, MetricError> {
+    let mut partitions: BTreeMap<String, Vec<RecordBatch>> = BTreeMap::new();
+    for batch in batches {
+        let names = cast(column(batch, "metric_name")?, &DataType::Utf8)?;
+        let dates = cast(
+            &cast(column(batch, "event_ts")?, &DataType::Date32)?,
+            &DataType::Utf8,
+        )?;
+        let mut rows: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+        for (row, (name, date)) in names
+            .as_string::<i32>()
+            .iter()
+            .zip(dates.as_string::<i32>().iter())
+            .enumerate()
+        {
+            let path = format!(
+                "metric_name={}/date={}",
+                partition_value(name),
+                partition_value(date)
+            );
+            rows.entry(path).or_default().push(row as u32);
+        }
+        let schema = batch.schema();
+        let kept: Vec<usize> = (0..schema.fields().len())
+            .filter(|&i| schema.field(i).name() != "metric_name")
+            .collect();
+        let batch = batch.project(&kept)?;
+        for (path, rows) in rows {
+            let rows = take_record_batch(&batch, &UInt32Array::from(rows))?;
+            partitions.entry(path).or_default().push(rows);
+        }
+    }
+    partitions
+        .into_iter()
+        .map(|(path, batches)| {
+            let batch = concat_batches(&batches[0].schema(), &batches)?;
+            Ok(Partition { path, batch })
+        })
+        .collect()
+}
+
+/// Returns a file name unique across runs, threads and processes, e.g. `part-<nanos>-<pid>-<n>.parquet`.
+pub(crate) fn part_name(extension: &str) -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "part-{}-{}-{}.{}",
+        nanos,
+        std::process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed),
+        extension
+    )
+}
+
+/// Returns when the file named by `part_name` was created.
+pub(crate) fn part_created(name: &str) -> Option<SystemTime> {
+    let nanos = name
+        .strip_prefix("part-")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()?;
+    Some(UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+fn column<'a>(batch: &'a RecordBatch, name: &str) -> Result<&'a ArrayRef, MetricError> {
+    batch
+        .column_by_name(name)
+        .ok_or_else(|| MetricError::MissingColumn(name.to_string()))
+}
+
+/// Escapes the characters not allowed in a partition directory the way Hive does.
+fn partition_value(value: Option<&str>) -> String {
+    match value {
+        Some(value) if !value.is_empty() => value
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' | '=' | '%' | ':' | '"' | '\'' | '#' | '?' | '*' | '<' | '>' | '|'
+                | '[' | ']' | '^' | '{' => format!("%{:02X}", c as u32),
+                c if c.is_control() => format!("%{:02X}", c as u32),
+                c => c.to_string(),
+            })
+            .collect(),
+        _ => DEFAULT_PARTITION.to_string(),
+    }
+}
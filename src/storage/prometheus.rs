@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use arrow::array::{Array, AsArray, RecordBatch};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Float64Type, Int64Type};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::storage::column;
+use crate::MetricError;
+
+/// Text format of the scraped metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ExpositionFormat {
+    /// The Prometheus text format, version 0.0.4.
+    #[default]
+    Prometheus,
+    /// The OpenMetrics text format, version 1.0.0.
+    OpenMetrics,
+}
+
+impl ExpositionFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExpositionFormat::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            ExpositionFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+        }
+    }
+}
+
+/// Renders metric results as gauges in the text `format`.
+///
+/// `metric_name` becomes the metric name and every `key=value` of `tags` a label, a tag without
+/// a value becomes a `<tag>="true"` label. The `<name>_bucket` series labeled with `le` along
+/// with `<name>_sum` and `<name>_count`, e.g. the ones of the histogram built-in, are rendered as
+/// a single `<name>` histogram instead. Names are sanitized to the characters allowed by
+/// Prometheus and label values are escaped. When a series has several rows, e.g. one per window,
+/// only the one with the newest `event_ts` is rendered, and rows without a value are skipped.
+pub fn render(batches: &[RecordBatch], format: ExpositionFormat) -> Result<String, MetricError> {
+    let registry = MetricsRegistry::default();
+    registry.update(batches)?;
+    Ok(registry.render(format))
+}
+
+/// A sample of a series, the value with the `event_ts` it was measured at.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    event_ts: Option<i64>,
+    value: f64,
+}
+
+/// Series by rendered labels, by sanitized metric name.
+type Families = BTreeMap<String, BTreeMap<String, Sample>>;
+
+/// The latest value of every series published to `StorageBackend::Prometheus`, shared with the
+/// `MetricsServer` scraped by Prometheus.
+///
+/// Clones share the same values, so a registry can be published to by several managers.
+#[derive(Debug, Clone, Default)]
+pub struct MetricsRegistry {
+    families: Arc<RwLock<Families>>,
+}
+
+impl PartialEq for MetricsRegistry {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.families, &other.families)
+    }
+}
+
+impl MetricsRegistry {
+    /// Records the values of `batches`, replacing the older values of the same series.
+    pub fn update(&self, batches: &[RecordBatch]) -> Result<(), MetricError> {
+        let mut samples = Vec::new();
+        for batch in batches {
+            let names = cast(column(batch, "metric_name")?, &DataType::Utf8)?;
+            let values = cast(column(batch, "value")?, &DataType::Float64)?;
+            let tags = match batch.column_by_name("tags") {
+                Some(tags) => Some(cast(tags, &DataType::Utf8)?),
+                None => None,
+            };
+            let event_ts = match batch.column_by_name("event_ts") {
+                Some(event_ts) => Some(cast(event_ts, &DataType::Int64)?),
+                None => None,
+            };
+            let (names, values) = (
+                names.as_string::<i32>(),
+                values.as_primitive::<Float64Type>(),
+            );
+            for row in 0..batch.num_rows() {
+                if names.is_null(row) || values.is_null(row) {
+                    continue;
+                }
+                let tags = tags
+                    .as_ref()
+                    .map(|tags| tags.as_string::<i32>())
+                    .filter(|tags| tags.is_valid(row))
+                    .map_or("", |tags| tags.value(row));
+                let event_ts = event_ts
+                    .as_ref()
+                    .map(|event_ts| event_ts.as_primitive::<Int64Type>())
+                    .filter(|event_ts| event_ts.is_valid(row))
+                    .map(|event_ts| event_ts.value(row));
+                let sample = Sample {
+                    event_ts,
+                    value: values.value(row),
+                };
+                samples.push((metric_name(names.value(row)), labels(tags), sample));
+            }
+        }
+
+        let mut families = self.families.write().unwrap_or_else(|e| e.into_inner());
+        for (name, labels, sample) in samples {
+            let series = families.entry(name).or_default();
+            match series.get(&labels) {
+                Some(current) if current.event_ts > sample.event_ts => {}
+                _ => {
+                    series.insert(labels, sample);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the latest values in the text `format`.
+    pub fn render(&self, format: ExpositionFormat) -> String {
+        let families = self.families.read().unwrap_or_else(|e| e.into_inner());
+        let histograms: BTreeSet<&str> = families
+            .iter()
+            .filter_map(|(name, series)| {
+                let base = name.strip_suffix("_bucket")?;
+                let is_histogram = series.keys().all(|labels| has_le(labels))
+                    && families.contains_key(&format!("{}_sum", base))
+                    && families.contains_key(&format!("{}_count", base));
+                is_histogram.then_some(base)
+            })
+            .collect();
+        let is_histogram_part = |name: &str| {
+            ["_sum", "_count"].iter().any(|suffix| {
+                name.strip_suffix(suffix)
+                    .is_some_and(|base| histograms.contains(base))
+            })
+        };
+
+        let mut text = String::new();
+        for (name, series) in families.iter() {
+            if is_histogram_part(name) {
+                continue;
+            }
+            match name
+                .strip_suffix("_bucket")
+                .filter(|b| histograms.contains(b))
+            {
+                Some(base) => {
+                    let _ = writeln!(text, "# TYPE {} histogram", base);
+                    for suffix in ["_bucket", "_sum", "_count"] {
+                        let name = format!("{}{}", base, suffix);
+                        write_series(&mut text, &name, &families[&name]);
+                    }
+                }
+                None => {
+                    let _ = writeln!(text, "# TYPE {} gauge", name);
+                    write_series(&mut text, name, series);
+                }
+            }
+        }
+        if format == ExpositionFormat::OpenMetrics {
+            text.push_str("# EOF\n");
+        }
+        text
+    }
+}
+
+fn write_series(text: &mut String, name: &str, series: &BTreeMap<String, Sample>) {
+    for (labels, sample) in series {
+        let _ = writeln!(text, "{}{} {}", name, labels, number(sample.value));
+    }
+}
+
+/// Whether the rendered `labels` hold the `le` label of a histogram bucket.
+fn has_le(labels: &str) -> bool {
+    labels.starts_with("{le=\"") || labels.contains(",le=\"")
+}
+
+/// An HTTP server exposing the values of a `MetricsRegistry` on `/metrics`.
+///
+/// The format is OpenMetrics when the scraper accepts it, the Prometheus text format otherwise.
+/// The server runs on the tokio runtime until it is shut down or dropped.
+#[derive(Debug)]
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+impl MetricsServer {
+    /// Binds the server to `addr` and starts serving `registry`, use port 0 for any free port.
+    pub async fn bind(
+        addr: impl ToSocketAddrs,
+        registry: MetricsRegistry,
+    ) -> Result<MetricsServer, MetricError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let registry = registry.clone();
+                // a failing connection only concerns its scraper
+                tokio::spawn(async move {
+                    let _ = respond(stream, &registry).await;
+                });
+            }
+        });
+        Ok(MetricsServer { local_addr, handle })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown(&self) {
+        self.handle.abort();
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Largest request head read from a scraper.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Longest time a scraper is given to send its request head.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Answers the request of a single connection, which is closed afterwards.
+async fn respond(mut stream: TcpStream, registry: &MetricsRegistry) -> std::io::Result<()> {
+    let request = timeout(READ_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = (request_line.next(), request_line.next());
+    let accepts_openmetrics = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("accept") && value.contains("application/openmetrics-text")
+        })
+    });
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some(path)) if path.split('?').next() == Some("/metrics") => {
+            let format = match accepts_openmetrics {
+                true => ExpositionFormat::OpenMetrics,
+                false => ExpositionFormat::Prometheus,
+            };
+            ("200 OK", format.content_type(), registry.render(format))
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads the request head, up to `MAX_REQUEST_SIZE` bytes.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < MAX_REQUEST_SIZE {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    Ok(request)
+}
+
+/// Sanitizes `name` to `[a-zA-Z_:][a-zA-Z0-9_:]*`, e.g. `payload.user.id_count_null` becomes
+/// `payload_user_id_count_null`.
+fn metric_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Sanitizes `name` to `[a-zA-Z_][a-zA-Z0-9_]*`.
+fn label_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn sanitize(name: &str, allowed: impl Fn(char) -> bool) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if allowed(c) { c } else { '_' })
+        .collect();
+    if sanitized.is_empty() || sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Renders the comma separated `tags` as a label set, e.g. `{env="prod"}`, repeated label names
+/// keep their first value. The `value` tag appended by `top_k` is percent-decoded, the other
+/// tags are rendered as they are.
+fn labels(tags: &str) -> String {
+    let mut labels: Vec<(String, &str)> = Vec::new();
+    for tag in tags.split(',').filter(|tag| !tag.is_empty()) {
+        let (name, value) = tag.split_once('=').unwrap_or((tag, "true"));
+        let name = label_name(name.trim());
+        if !labels.iter().any(|(n, _)| *n == name) {
+            labels.push((name, value));
+        }
+    }
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| match name.as_str() {
+            "value" => format!("{}=\"{}\"", name, escape(&decode(value))),
+            _ => format!("{}=\"{}\"", name, escape(value)),
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Decodes the `,`, `=` and `%` characters percent-encoded by `top_k` in the value of a tag.
+fn decode(value: &str) -> String {
+    value
+        .replace("%2C", ",")
+        .replace("%3D", "=")
+        .replace("%25", "%")
+}
+
+/// Escapes the backslashes, double quotes and line feeds of a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn number(value: f64) -> String {
+    match value {
+        v if v.is_nan() => "NaN".to_string(),
+        v if v == f64::INFINITY => "+Inf".to_string(),
+        v if v == f64::NEG_INFINITY => "-Inf".to_string(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use arrow::array::{Float64Array, Int64Array, RecordBatch, StringArray};
+    use arrow::datatypes::{DataType, Field, Schema};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::core::computing::execute;
+    use crate::core::definition::{Buckets, BuiltInMetricsBuilder};
+    use crate::metrics::MetricsManager;
+    use crate::storage::StorageBackend;
+    use crate::test::generate_dataset;
+
+    use super::{render, ExpositionFormat, MetricsRegistry, MetricsServer};
+
+    #[test]
+    fn test_render() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("value", DataType::Float64, true),
+            Field::new("metric_name", DataType::Utf8, false),
+            Field::new("tags", DataType::Utf8, false),
+            Field::new("event_ts", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Float64Array::from(vec![
+                    Some(1.0),
+                    Some(2.5),
+                    Some(f64::INFINITY),
+                    None,
+                ])),
+                Arc::new(StringArray::from(vec![
+                    "payload.user.id_count_null",
+                    "payload.user.id_count_null",
+                    "9lives",
+                    "value_count_null",
+                ])),
+                Arc::new(StringArray::from(vec![
+                    "env=prod",
+                    "env=prod",
+                    "path=C:\\tmp \"x\",critical,env=a,env=b,value=x%2Cy%3D100%25,note=50%25",
+                    "",
+                ])),
+                Arc::new(Int64Array::from(vec![2, 1, 1, 1])),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            render(std::slice::from_ref(&batch), ExpositionFormat::Prometheus).unwrap(),
+            "# TYPE _9lives gauge\n\
+             _9lives{path=\"C:\\\\tmp \\\"x\\\"\",critical=\"true\",env=\"a\",value=\"x,y=100%\",note=\"50%25\"} +Inf\n\
+             # TYPE payload_user_id_count_null gauge\n\
+             payload_user_id_count_null{env=\"prod\"} 1\n"
+        );
+        assert!(render(&[batch], ExpositionFormat::OpenMetrics)
+            .unwrap()
+            .ends_with("payload_user_id_count_null{env=\"prod\"} 1\n# EOF\n"));
+    }
+
+    #[tokio::test]
+    async fn test_render_histogram() {
+        let result = execute(
+            vec![generate_dataset().unwrap()],
+            &BuiltInMetricsBuilder::new().histogram("value", Buckets::Explicit(vec![5.0]), None),
+        )
+        .await
+        .unwrap();
+
+        let text = render(&result, ExpositionFormat::Prometheus).unwrap();
+        // the sum is the one of the Float32 values
+        assert!(text.starts_with(
+            "# TYPE value_histogram histogram\n\
+             value_histogram_bucket{le=\"+Inf\"} 4\n\
+             value_histogram_bucket{le=\"5\"} 2\n\
+             value_histogram_sum 28.8"
+        ));
+        assert!(text.contains(
+            "value_histogram_count 4\n\
+             # TYPE value_histogram_bucket_count gauge\n"
+        ));
+        assert!(!text.contains("# TYPE value_histogram_sum"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_server() {
+        let registry = MetricsRegistry::default();
+        let server = MetricsServer::bind("127.0.0.1:0", registry.clone())
+            .await
+            .unwrap();
+        MetricsManager::default()
+            .transform(BuiltInMetricsBuilder::new().count_null("value", Some(vec!["env=test"])))
+            .execute(vec![generate_dataset().unwrap()])
+            .publish(StorageBackend::Prometheus(registry))
+            .await
+            .unwrap();
+
+        let scrape = |request: &'static str| {
+            let addr = server.local_addr();
+            async move {
+                let mut stream = TcpStream::connect(addr).await.unwrap();
+                stream.write_all(request.as_bytes()).await.unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await.unwrap();
+                response
+            }
+        };
+        let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4"));
+        assert!(response.ends_with(
+            "\r\n\r\n# TYPE value_count_null gauge\nvalue_count_null{env=\"test\"} 1\n"
+        ));
+
+        let response = scrape(
+            "GET /metrics HTTP/1.1\r\nAccept: application/openmetrics-text; version=1.0.0\r\n\r\n",
+        )
+        .await;
+        assert!(response.ends_with("# EOF\n"));
+        let response = scrape("GET / HTTP/1.1\r\n\r\n").await;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        server.shutdown();
+    }
+}
@@ -0,0 +1,253 @@
+use std::fmt::Debug;
+
+use arrow::array::RecordBatch;
+use object_store::aws::{AmazonS3, AmazonS3Builder};
+use object_store::path::Path;
+use object_store::{ObjectStore, PutPayload};
+
+use crate::storage::{part_name, partitions, FileFormat, Partition, WriteMode};
+use crate::MetricError;
+
+/// Configuration of `StorageBackend::S3`.
+///
+/// Metric results are written under `prefix` in `bucket` with the layout of the local disk
+/// backend, `metric_name=<name>/date=<YYYY-MM-DD>/part-*.<extension>`. Objects cannot be appended
+/// to, so every publish adds new objects or replaces those of the partitions being written.
+///
+/// Settings left unset are read from the standard `AWS_*` environment variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Config {
+    pub bucket: String,
+    pub prefix: String,
+    pub region: Option<String>,
+    /// Endpoint of an S3 compatible store such as MinIO, e.g. `http://localhost:9000`.
+    pub endpoint: Option<String>,
+    pub credentials: Option<S3Credentials>,
+    pub format: FileFormat,
+    pub mode: WriteMode,
+}
+
+/// Static credentials of `S3Config`, the secrets are left out of the `Debug` output.
+#[derive(Clone, PartialEq)]
+pub struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl S3Credentials {
+    pub fn new(access_key_id: impl Into<String>, secret_access_key: impl Into<String>) -> Self {
+        Self {
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret_access_key.into(),
+            session_token: None,
+        }
+    }
+
+    pub fn with_session_token(mut self, session_token: impl Into<String>) -> Self {
+        self.session_token = Some(session_token.into());
+        self
+    }
+}
+
+impl Debug for S3Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Credentials")
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &"***")
+            .field("session_token", &self.session_token.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl S3Config {
+    /// Creates a configuration appending Parquet files at the root of `bucket`.
+    pub fn new(bucket: impl Into<String>) -> Self {
+        Self {
+            bucket: bucket.into(),
+            prefix: String::new(),
+            region: None,
+            endpoint: None,
+            credentials: None,
+            format: FileFormat::default(),
+            mode: WriteMode::default(),
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    pub fn with_region(mut self, region: impl Into<String>) -> Self {
+        self.region = Some(region.into());
+        self
+    }
+
+    pub fn with_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.endpoint = Some(endpoint.into());
+        self
+    }
+
+    pub fn with_credentials(mut self, credentials: S3Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    pub fn with_format(mut self, format: FileFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_mode(mut self, mode: WriteMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Builds the store of the bucket, plain HTTP is only allowed for an `http://` endpoint.
+    pub fn object_store(&self) -> Result<AmazonS3, MetricError> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(&self.bucket);
+        if let Some(region) = &self.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(endpoint) = &self.endpoint {
+            builder = builder
+                .with_allow_http(endpoint.starts_with("http://"))
+                .with_endpoint(endpoint);
+        }
+        if let Some(credentials) = &self.credentials {
+            builder = builder
+                .with_access_key_id(&credentials.access_key_id)
+                .with_secret_access_key(&credentials.secret_access_key);
+            if let Some(token) = &credentials.session_token {
+                builder = builder.with_token(token);
+            }
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Writes `batches` to `store` under the prefix of `config`, returning the written objects.
+///
+/// The store is usually `S3Config::object_store`, any other store gets the same layout.
+pub async fn write(
+    store: &dyn ObjectStore,
+    config: &S3Config,
+    batches: &[RecordBatch],
+) -> Result<Vec<Path>, MetricError> {
+    let extension = config.format.extension();
+    let mut objects = Vec::new();
+    for Partition { path, batch } in partitions(batches)? {
+        let directory = match config.prefix.trim_matches('/') {
+            "" => Path::parse(path),
+            prefix => Path::parse(format!("{}/{}", prefix, path)),
+        }
+        .map_err(object_store::Error::from)?;
+        let existing = match config.mode {
+            WriteMode::Append => Vec::new(),
+            WriteMode::Overwrite => store
+                .list_with_delimiter(Some(&directory))
+                .await?
+                .objects
+                .into_iter()
+                .map(|object| object.location)
+                .filter(|location| {
+                    location.filename().is_some_and(|name| {
+                        name.starts_with("part-") && name.ends_with(&format!(".{}", extension))
+                    })
+                })
+                .collect(),
+        };
+        let object = directory.child(part_name(extension));
+        let bytes = config.format.encode(&batch, true)?;
+        store.put(&object, PutPayload::from(bytes)).await?;
+        // replaced objects are removed once the new one is in place, the partition is never empty
+        for old in existing {
+            store.delete(&old).await?;
+        }
+        objects.push(object);
+    }
+    Ok(objects)
+}
+
+#[cfg(test)]
+mod test {
+    use object_store::memory::InMemory;
+    use object_store::path::Path;
+    use object_store::ObjectStore;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::core::computing::execute;
+    use crate::core::definition::BuiltInMetricsBuilder;
+    use crate::storage::{FileFormat, WriteMode};
+    use crate::test::{column_as_f64, generate_dataset};
+
+    use super::{write, S3Config, S3Credentials};
+
+    #[tokio::test]
+    async fn test_write_objects() {
+        let store = InMemory::new();
+        let config = S3Config::new("metrics").with_prefix("/observability/");
+        let result = execute(
+            vec![generate_dataset().unwrap()],
+            &BuiltInMetricsBuilder::new().count_null("value", None),
+        )
+        .await
+        .unwrap();
+
+        let first = write(&store, &config, &result).await.unwrap();
+        write(&store, &config, &result).await.unwrap();
+        assert!(first[0]
+            .as_ref()
+            .starts_with("observability/metric_name=value_count_null/date="));
+        assert!(first[0].as_ref().ends_with(".parquet"));
+
+        let listed = |prefix: Path| {
+            let store = &store;
+            async move {
+                store
+                    .list_with_delimiter(Some(&prefix))
+                    .await
+                    .unwrap()
+                    .objects
+            }
+        };
+        let partition = Path::from_iter(first[0].parts().take(3));
+        assert_eq!(listed(partition.clone()).await.len(), 2);
+
+        let bytes = store.get(&first[0]).await.unwrap().bytes().await.unwrap();
+        let metrics = ParquetRecordBatchReaderBuilder::try_new(bytes)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(column_as_f64(&metrics, "value"), vec![Some(1.0)]);
+
+        let config = config
+            .with_format(FileFormat::NdJson)
+            .with_mode(WriteMode::Overwrite);
+        write(&store, &config, &result).await.unwrap();
+        write(&store, &config, &result).await.unwrap();
+        let objects = listed(partition).await;
+        // parquet objects are kept, only the objects of the written format are replaced
+        assert_eq!(objects.len(), 3);
+        assert_eq!(
+            objects
+                .iter()
+                .filter(|o| o.location.as_ref().ends_with(".json"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_object_store_with_endpoint() {
+        let config = S3Config::new("metrics")
+            .with_region("us-east-1")
+            .with_endpoint("http://localhost:9000")
+            .with_credentials(S3Credentials::new("minio", "minio123").with_session_token("token"));
+        assert!(config.object_store().is_ok());
+        assert!(!format!("{:?}", config).contains("minio123"));
+    }
+}
@@ -1,7 +1,13 @@
-use arrow::array::{Float32Array, Int32Array, RecordBatch, StringArray};
-use arrow::datatypes::{DataType, Field, Schema};
+use arrow::array::{
+    Array, ArrayRef, Float32Array, Float64Array, Int32Array, ListBuilder, MapBuilder, RecordBatch,
+    StringArray, StringBuilder, StructArray, TimestampNanosecondArray,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
 use arrow::error::ArrowError;
+use std::collections::HashMap;
 use std::iter::zip;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 pub fn generate_dataset() -> Result<RecordBatch, ArrowError> {
@@ -22,6 +28,109 @@ pub fn generate_dataset() -> Result<RecordBatch, ArrowError> {
     RecordBatch::try_new(schem.clone(), vec![col_id, col_category, col_value])
 }
 
+/// Generates a reference for `generate_dataset` where `category` has a new value `d` and
+/// `value` has no null values.
+pub fn generate_reference_dataset() -> Result<RecordBatch, ArrowError> {
+    let col_id = Arc::new(Int32Array::from(vec![1, 2, 3, 4]));
+    let col_category = Arc::new(StringArray::from(vec!["a", "b", "b", "d"]));
+    let col_value = Arc::new(Float32Array::from(vec![
+        Some(1.0),
+        Some(5.0),
+        Some(7.0),
+        Some(20.0),
+    ]));
+    let schem = Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int32, false),
+        Field::new("category", DataType::Utf8, false),
+        Field::new("value", DataType::Float32, true),
+    ]));
+    RecordBatch::try_new(schem.clone(), vec![col_id, col_category, col_value])
+}
+
+/// Generates events spread over three hours, `event_time` minutes are 10, 50, 80, 90 and 125.
+pub fn generate_events_dataset() -> Result<RecordBatch, ArrowError> {
+    let minute = 60_000_000_000;
+    let col_event_time = Arc::new(TimestampNanosecondArray::from(vec![
+        10 * minute,
+        50 * minute,
+        80 * minute,
+        90 * minute,
+        125 * minute,
+    ]));
+    let col_value = Arc::new(Float32Array::from(vec![
+        Some(1.0),
+        None,
+        None,
+        Some(4.0),
+        Some(5.0),
+    ]));
+    let schema = Arc::new(Schema::new(vec![
+        Field::new(
+            "event_time",
+            DataType::Timestamp(TimeUnit::Nanosecond, None),
+            false,
+        ),
+        Field::new("value", DataType::Float32, true),
+    ]));
+    RecordBatch::try_new(schema, vec![col_event_time, col_value])
+}
+
+/// Generates free text values, `email` holds two valid addresses, a blank and a null value.
+pub fn generate_text_dataset() -> Result<RecordBatch, ArrowError> {
+    let col_email = Arc::new(StringArray::from(vec![
+        Some("jane@example.com"),
+        Some("john@example.org"),
+        Some("not an email"),
+        Some("  "),
+        None,
+    ]));
+    let schema = Arc::new(Schema::new(vec![Field::new("email", DataType::Utf8, true)]));
+    RecordBatch::try_new(schema, vec![col_email])
+}
+
+/// Generates a `payload` struct with a nested `user.id` of 1, null and 3 plus an `items` list
+/// of lengths 2, 0 and null, and an `attributes` map with the keys k1, k2 and k3.
+pub fn generate_nested_dataset() -> Result<RecordBatch, ArrowError> {
+    let id: ArrayRef = Arc::new(Int32Array::from(vec![Some(1), None, Some(3)]));
+    let user = StructArray::from(vec![(
+        Arc::new(Field::new("id", DataType::Int32, true)),
+        id,
+    )]);
+    let mut items = ListBuilder::new(StringBuilder::new());
+    items.append_value([Some("a"), Some("b")]);
+    items.append_value(Vec::<Option<&str>>::new());
+    items.append_null();
+    let items = items.finish();
+    let payload = StructArray::from(vec![
+        (
+            Arc::new(Field::new("user", user.data_type().clone(), false)),
+            Arc::new(user) as ArrayRef,
+        ),
+        (
+            Arc::new(Field::new("items", items.data_type().clone(), true)),
+            Arc::new(items) as ArrayRef,
+        ),
+    ]);
+    let mut attributes = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
+    for entries in [
+        vec![("k1", "x"), ("k2", "y")],
+        vec![("k2", "z")],
+        vec![("k3", "w")],
+    ] {
+        for (key, value) in entries {
+            attributes.keys().append_value(key);
+            attributes.values().append_value(value);
+        }
+        attributes.append(true)?;
+    }
+    let attributes = attributes.finish();
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("payload", payload.data_type().clone(), false),
+        Field::new("attributes", attributes.data_type().clone(), false),
+    ]));
+    RecordBatch::try_new(schema, vec![Arc::new(payload), Arc::new(attributes)])
+}
+
 pub fn assert_record_batches_equal(
     actual_records: Vec<RecordBatch>,
     expected_records: Vec<RecordBatch>,
@@ -48,3 +157,48 @@ pub fn assert_record_batches_equal(
         );
     }
 }
+
+/// Collects the values of `column` across all the batches, cast to `Float64`.
+pub fn column_as_f64(batches: &[RecordBatch], column: &str) -> Vec<Option<f64>> {
+    batches
+        .iter()
+        .flat_map(|batch| {
+            let array = cast(batch.column_by_name(column).unwrap(), &DataType::Float64).unwrap();
+            let array = array.as_any().downcast_ref::<Float64Array>().unwrap();
+            (0..array.len())
+                .map(|i| array.is_valid(i).then(|| array.value(i)))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Collects the values of `column` across all the batches, cast to `Utf8`.
+pub fn column_as_string(batches: &[RecordBatch], column: &str) -> Vec<Option<String>> {
+    batches
+        .iter()
+        .flat_map(|batch| {
+            let array = cast(batch.column_by_name(column).unwrap(), &DataType::Utf8).unwrap();
+            let array = array.as_any().downcast_ref::<StringArray>().unwrap();
+            (0..array.len())
+                .map(|i| array.is_valid(i).then(|| array.value(i).to_string()))
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Returns the `value` of every metric by its `metric_name`, both expected to be non-null.
+pub fn metrics_by_name(batches: &[RecordBatch]) -> HashMap<String, f64> {
+    column_as_string(batches, "metric_name")
+        .into_iter()
+        .zip(column_as_f64(batches, "value"))
+        .map(|(name, value)| (name.unwrap(), value.unwrap()))
+        .collect()
+}
+
+/// Creates an empty directory for the files written by a test, unique to `name` and the process.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("df-metrics-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}